@@ -0,0 +1,228 @@
+//! `#[derive(Coords)]` for the `kdbush::kdbush::Coords` trait.
+//!
+//! The x and y accessors are declared either on the struct:
+//!
+//! ```ignore
+//! #[derive(Coords)]
+//! #[coords(x = "lon", y = "lat")]
+//! struct City { lon: f64, lat: f64 }
+//! ```
+//!
+//! or on the fields themselves with `#[coords(x)]` and `#[coords(y)]`.
+//! A struct level accessor is a `.` separated path of fields, tuple indexes
+//! and getter methods, e.g. `"location.lon"`, `"0"` or `"position().x"`.
+//! `CoordType` is taken from the field type when the accessor is a plain
+//! field, otherwise it has to be given with `ty = "f64"`. Inside the kdbush
+//! crate itself use `crate = "crate"` to point the impl at the local trait.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Index, LitStr, Member, Type};
+
+#[proc_macro_derive(Coords, attributes(coords))]
+pub fn derive_coords(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+enum Segment {
+    Field(Member),
+    Getter(Ident),
+}
+
+struct Accessor {
+    segments: Vec<Segment>,
+    span: Span,
+}
+
+impl Accessor {
+    fn parse(lit: &LitStr) -> syn::Result<Accessor> {
+        let value = lit.value();
+        let mut segments = vec![];
+        for part in value.split('.') {
+            let part = part.trim();
+            let segment = if let Some(name) = part.strip_suffix("()") {
+                Segment::Getter(syn::parse_str::<Ident>(name).map_err(|_| {
+                    syn::Error::new(lit.span(), format!("invalid getter `{}`", part))
+                })?)
+            } else if let Ok(i) = part.parse::<u32>() {
+                Segment::Field(Member::Unnamed(Index {
+                    index: i,
+                    span: lit.span(),
+                }))
+            } else {
+                Segment::Field(Member::Named(syn::parse_str::<Ident>(part).map_err(
+                    |_| syn::Error::new(lit.span(), format!("invalid field `{}`", part)),
+                )?))
+            };
+            segments.push(segment);
+        }
+        Ok(Accessor {
+            segments,
+            span: lit.span(),
+        })
+    }
+
+    fn from_member(member: Member, span: Span) -> Accessor {
+        Accessor {
+            segments: vec![Segment::Field(member)],
+            span,
+        }
+    }
+
+    fn to_tokens(&self) -> TokenStream2 {
+        let mut tokens = quote!(self);
+        for segment in &self.segments {
+            tokens = match segment {
+                Segment::Field(member) => quote!(#tokens.#member),
+                Segment::Getter(name) => quote!(#tokens.#name()),
+            };
+        }
+        tokens
+    }
+
+    /// The member this accessor reads if it is a plain top level field.
+    fn top_level_field(&self) -> Option<&Member> {
+        match self.segments.as_slice() {
+            [Segment::Field(member)] => Some(member),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    x: Option<Accessor>,
+    y: Option<Accessor>,
+    ty: Option<Type>,
+    krate: Option<syn::Path>,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Coords can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut options = Options::default();
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("coords")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("x") {
+                let lit: LitStr = meta.value()?.parse()?;
+                set_once(&mut options.x, Accessor::parse(&lit)?, &meta.path, "x")
+            } else if meta.path.is_ident("y") {
+                let lit: LitStr = meta.value()?.parse()?;
+                set_once(&mut options.y, Accessor::parse(&lit)?, &meta.path, "y")
+            } else if meta.path.is_ident("ty") {
+                let lit: LitStr = meta.value()?.parse()?;
+                set_once(&mut options.ty, lit.parse()?, &meta.path, "ty")
+            } else if meta.path.is_ident("crate") {
+                let lit: LitStr = meta.value()?.parse()?;
+                set_once(&mut options.krate, lit.parse()?, &meta.path, "crate")
+            } else {
+                Err(meta.error("expected `x`, `y`, `ty` or `crate`"))
+            }
+        })?;
+    }
+
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("coords")) {
+            attr.parse_nested_meta(|meta| {
+                let accessor = Accessor::from_member(member.clone(), attr.pound_token.span);
+                if meta.path.is_ident("x") {
+                    set_once(&mut options.x, accessor, &meta.path, "x")
+                } else if meta.path.is_ident("y") {
+                    set_once(&mut options.y, accessor, &meta.path, "y")
+                } else {
+                    Err(meta.error("expected `x` or `y`"))
+                }
+            })?;
+        }
+    }
+
+    let x = options.x.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing x coordinate, add #[coords(x = \"...\")]",
+        )
+    })?;
+    let y = options.y.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing y coordinate, add #[coords(y = \"...\")]",
+        )
+    })?;
+    let ty = match options.ty {
+        Some(ty) => ty,
+        None => field_type(fields, &x)?,
+    };
+
+    let krate = options.krate.unwrap_or_else(|| syn::parse_quote!(::kdbush));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let get_x = x.to_tokens();
+    let get_y = y.to_tokens();
+
+    Ok(quote! {
+        impl #impl_generics #krate::kdbush::Coords for #name #ty_generics #where_clause {
+            type CoordType = #ty;
+            fn get_x(&self) -> Self::CoordType {
+                #get_x
+            }
+            fn get_y(&self) -> Self::CoordType {
+                #get_y
+            }
+            fn get(&self, i: i8) -> Self::CoordType {
+                match i {
+                    0 => #get_x,
+                    _ => #get_y,
+                }
+            }
+        }
+    })
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T, path: &syn::Path, name: &str) -> syn::Result<()> {
+    if slot.is_some() {
+        return Err(syn::Error::new_spanned(
+            path,
+            format!("duplicate `{}` option", name),
+        ));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+fn field_type(fields: &Fields, accessor: &Accessor) -> syn::Result<Type> {
+    let err = || {
+        syn::Error::new(
+            accessor.span,
+            "cannot infer CoordType for a nested field or getter, add #[coords(ty = \"...\")]",
+        )
+    };
+    let member = accessor.top_level_field().ok_or_else(err)?;
+    fields
+        .iter()
+        .enumerate()
+        .find(|(i, field)| match (member, &field.ident) {
+            (Member::Named(name), Some(ident)) => name == ident,
+            (Member::Unnamed(index), None) => index.index as usize == *i,
+            _ => false,
+        })
+        .map(|(_, field)| field.ty.clone())
+        .ok_or_else(|| syn::Error::new(accessor.span, "no such field"))
+}
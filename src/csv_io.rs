@@ -0,0 +1,215 @@
+//! Reading points from CSV files with coordinate columns, and writing query
+//! results back out as CSV.
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::kdbush::{Coords, KDBush};
+
+/// A column of a CSV file, by header name or by position.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+impl From<&str> for Column {
+    fn from(name: &str) -> Column {
+        Column::Name(name.to_string())
+    }
+}
+
+impl From<usize> for Column {
+    fn from(index: usize) -> Column {
+        Column::Index(index)
+    }
+}
+
+/// A row of a CSV file with its coordinates, as stored in the index.
+#[derive(Debug, Clone, PartialEq, Coords)]
+#[coords(x = "x", y = "y", crate = "crate")]
+pub struct CsvRecord<T> {
+    pub x: f64,
+    pub y: f64,
+    /// The whole row, deserialized by header name if the file has headers
+    /// and by position otherwise.
+    pub record: T,
+}
+
+/// A row that was skipped while loading.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadRow {
+    /// Line of the row in the file, starting at 1.
+    pub line: u64,
+    pub message: String,
+}
+
+/// The index built from a CSV file and the rows left out of it.
+#[derive(Debug)]
+pub struct CsvPoints<T> {
+    pub index: KDBush<CsvRecord<T>>,
+    pub bad_rows: Vec<BadRow>,
+}
+
+/// Builds a `KDBush` from a CSV file, e.g. for a semicolon separated file
+/// with `lon` and `lat` columns:
+///
+/// ```ignore
+/// let points = CsvLoader::new("lon", "lat")
+///     .delimiter(b';')
+///     .load_path::<HashMap<String, String>, _>("shops.csv")?;
+/// ```
+///
+/// Rows with unparseable coordinates or fields are reported in `bad_rows`
+/// instead of failing the whole load.
+#[derive(Debug, Clone)]
+pub struct CsvLoader {
+    x: Column,
+    y: Column,
+    delimiter: u8,
+    has_headers: bool,
+    node_size: usize,
+}
+
+impl CsvLoader {
+    pub fn new<X: Into<Column>, Y: Into<Column>>(x: X, y: Y) -> CsvLoader {
+        CsvLoader {
+            x: x.into(),
+            y: y.into(),
+            delimiter: b',',
+            has_headers: true,
+            node_size: 64,
+        }
+    }
+
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Whether the first row holds the column names, true by default.
+    pub fn has_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    pub fn node_size(mut self, node_size: usize) -> Self {
+        self.node_size = node_size;
+        self
+    }
+
+    pub fn load_path<T, P>(&self, path: P) -> Result<CsvPoints<T>, Error>
+    where
+        T: DeserializeOwned,
+        P: AsRef<Path>,
+    {
+        self.load(File::open(path)?)
+    }
+
+    pub fn load<T, R>(&self, reader: R) -> Result<CsvPoints<T>, Error>
+    where
+        T: DeserializeOwned,
+        R: Read,
+    {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(self.has_headers)
+            .from_reader(reader);
+        let headers = if self.has_headers {
+            Some(reader.headers()?.clone())
+        } else {
+            None
+        };
+        let column = |column: &Column| match (column, &headers) {
+            (Column::Index(i), _) => Ok(*i),
+            (Column::Name(name), Some(headers)) => {
+                headers.iter().position(|h| h == name).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("no column `{}`", name))
+                })
+            }
+            (Column::Name(name), None) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "column `{}` given by name, but the file has no headers",
+                    name
+                ),
+            )),
+        };
+        let (x, y) = (column(&self.x)?, column(&self.y)?);
+
+        let mut points = vec![];
+        let mut bad_rows = vec![];
+        let mut record = csv::StringRecord::new();
+        loop {
+            match reader.read_record(&mut record) {
+                Ok(false) => break,
+                Ok(true) => {}
+                Err(err) => match err.kind() {
+                    csv::ErrorKind::Io(_) => return Err(err.into()),
+                    _ => {
+                        bad_rows.push(BadRow {
+                            line: err.position().map_or(0, |p| p.line()),
+                            message: err.to_string(),
+                        });
+                        continue;
+                    }
+                },
+            }
+
+            let coord = |i: usize, axis: &str| -> Result<f64, String> {
+                let value = record.get(i).ok_or_else(|| format!("no {} column", axis))?;
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid {} `{}`", axis, value))
+            };
+            let row = coord(x, "x").and_then(|x| {
+                let y = coord(y, "y")?;
+                let record = record
+                    .deserialize(headers.as_ref())
+                    .map_err(|err| err.to_string())?;
+                Ok(CsvRecord { x, y, record })
+            });
+            match row {
+                Ok(row) => points.push(row),
+                Err(message) => bad_rows.push(BadRow {
+                    line: record.position().map_or(0, |p| p.line()),
+                    message,
+                }),
+            }
+        }
+
+        Ok(CsvPoints {
+            index: KDBush::new(points, self.node_size)?,
+            bad_rows,
+        })
+    }
+}
+
+/// Writes query results as CSV with the columns `id,x,y,distance`, where the
+/// ids are positions in `index.points` and `distance` is left empty for
+/// results without one, e.g. those of `range`.
+pub fn write_results<W, T, I>(writer: W, index: &KDBush<T>, results: I) -> Result<(), Error>
+where
+    W: Write,
+    T: Coords,
+    T::CoordType: Display,
+    I: IntoIterator<Item = (usize, Option<T::CoordType>)>,
+{
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["id", "x", "y", "distance"])?;
+    for (id, distance) in results {
+        let p = &index.points[id];
+        writer.write_record([
+            id.to_string(),
+            p.get_x().to_string(),
+            p.get_y().to_string(),
+            distance.map_or_else(String::new, |d| d.to_string()),
+        ])?;
+    }
+    writer.flush()
+}
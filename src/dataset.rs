@@ -0,0 +1,143 @@
+//! Loaders for the place datasets the geo queries are usually run on. They
+//! parse while reading, so the decompressed file is never held in memory.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Lines};
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::read::GzDecoder;
+
+use crate::kdbush::{City, Coords};
+
+/// Reads the gzipped JSON array of all-the-cities, `cities.json.gz`.
+pub fn load_cities_json_gz<P: AsRef<Path>>(path: P) -> Result<Vec<City>, Error> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// A record of a GeoNames dump such as `cities1000.txt` or
+/// `allCountries.txt`, see the readme of the GeoNames export for the fields.
+#[derive(Debug, Clone, PartialEq, Coords)]
+#[coords(x = "lon", y = "lat", crate = "crate")]
+pub struct GeoName {
+    pub geoname_id: u32,
+    pub name: String,
+    pub ascii_name: String,
+    pub alternate_names: Vec<String>,
+    pub lat: f64,
+    pub lon: f64,
+    pub feature_class: String,
+    pub feature_code: String,
+    pub country_code: String,
+    pub alt_country_codes: Vec<String>,
+    pub admin1_code: String,
+    pub admin2_code: String,
+    pub admin3_code: String,
+    pub admin4_code: String,
+    pub population: u64,
+    pub elevation: Option<i32>,
+    /// Digital elevation model height, -9999 for the sea.
+    pub dem: Option<i32>,
+    pub timezone: String,
+    /// Date of the last change, `yyyy-MM-dd`.
+    pub modification_date: String,
+}
+
+impl GeoName {
+    /// Reads a tab separated GeoNames dump, gzipped if the file name ends
+    /// in `.gz`.
+    pub fn load_tsv<P: AsRef<Path>>(path: P) -> Result<Vec<GeoName>, Error> {
+        let file = File::open(&path)?;
+        if path.as_ref().extension().is_some_and(|ext| ext == "gz") {
+            GeoNames::new(BufReader::new(GzDecoder::new(file))).collect()
+        } else {
+            GeoNames::new(BufReader::new(file)).collect()
+        }
+    }
+
+    fn parse(line: &str) -> Result<GeoName, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 19 {
+            return Err(format!("expected 19 fields, found {}", fields.len()));
+        }
+        let list = |i: usize| -> Vec<String> {
+            fields[i]
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+                .collect()
+        };
+
+        Ok(GeoName {
+            geoname_id: parse_field(&fields, 0, "geonameid")?,
+            name: fields[1].to_string(),
+            ascii_name: fields[2].to_string(),
+            alternate_names: list(3),
+            lat: parse_field(&fields, 4, "latitude")?,
+            lon: parse_field(&fields, 5, "longitude")?,
+            feature_class: fields[6].to_string(),
+            feature_code: fields[7].to_string(),
+            country_code: fields[8].to_string(),
+            alt_country_codes: list(9),
+            admin1_code: fields[10].to_string(),
+            admin2_code: fields[11].to_string(),
+            admin3_code: fields[12].to_string(),
+            admin4_code: fields[13].to_string(),
+            population: parse_optional(&fields, 14, "population")?.unwrap_or(0),
+            elevation: parse_optional(&fields, 15, "elevation")?,
+            dem: parse_optional(&fields, 16, "dem")?,
+            timezone: fields[17].to_string(),
+            modification_date: fields[18].to_string(),
+        })
+    }
+}
+
+fn parse_field<F: FromStr>(fields: &[&str], i: usize, name: &str) -> Result<F, String> {
+    fields[i]
+        .parse()
+        .map_err(|_| format!("invalid {} `{}`", name, fields[i]))
+}
+
+fn parse_optional<F: FromStr>(fields: &[&str], i: usize, name: &str) -> Result<Option<F>, String> {
+    match fields[i] {
+        "" => Ok(None),
+        _ => parse_field(fields, i, name).map(Some),
+    }
+}
+
+/// Iterator over the records of a GeoNames dump, one line at a time.
+pub struct GeoNames<R> {
+    lines: Lines<R>,
+    line: usize,
+}
+
+impl<R: BufRead> GeoNames<R> {
+    pub fn new(reader: R) -> GeoNames<R> {
+        GeoNames {
+            lines: reader.lines(),
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for GeoNames<R> {
+    type Item = Result<GeoName, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err)),
+            };
+            self.line += 1;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line_no = self.line;
+            return Some(GeoName::parse(&line).map_err(|msg| {
+                Error::new(ErrorKind::InvalidData, format!("line {}: {}", line_no, msg))
+            }));
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::{Error, ErrorKind};
+
+use num::{Float, Num, ToPrimitive};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::kdbush::BBox;
+
+/// Items indexed by a `Flatbush`, the rectangle counterpart of `Coords`.
+pub trait Bounds {
+    type CoordType: Num + PartialOrd;
+    fn get_min_x(&self) -> Self::CoordType;
+    fn get_min_y(&self) -> Self::CoordType;
+    fn get_max_x(&self) -> Self::CoordType;
+    fn get_max_y(&self) -> Self::CoordType;
+}
+
+impl<T> Bounds for BBox<T>
+where
+    T: Num + PartialOrd + Copy,
+{
+    type CoordType = T;
+    fn get_min_x(&self) -> T {
+        self.min_x
+    }
+    fn get_min_y(&self) -> T {
+        self.min_y
+    }
+    fn get_max_x(&self) -> T {
+        self.max_x
+    }
+    fn get_max_y(&self) -> T {
+        self.max_y
+    }
+}
+
+/// A static packed Hilbert R-tree, a port of mourner's flatbush.
+///
+/// Nodes are stored level by level in `boxes`, leaves first. For a leaf
+/// `indices` holds the position of the item in `items`, for a branch the
+/// position of its first child in `boxes`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: serde::Serialize, T::CoordType: serde::Serialize",
+    deserialize = "T: serde::Deserialize<'de>, T::CoordType: serde::Deserialize<'de>"
+))]
+pub struct Flatbush<T>
+where
+    T: Bounds,
+{
+    pub items: Vec<T>,
+    pub node_size: usize,
+    pub boxes: Vec<BBox<T::CoordType>>,
+    pub indices: Vec<usize>,
+    pub level_bounds: Vec<usize>,
+}
+
+impl<T> Flatbush<T>
+where
+    T: Bounds,
+    T::CoordType: Num + PartialOrd + Copy + ToPrimitive,
+{
+    pub fn new(items: Vec<T>, node_size: usize) -> Result<Flatbush<T>, Error> {
+        if node_size < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "node size should be at least 2",
+            ));
+        }
+
+        let mut index = Flatbush {
+            items,
+            node_size,
+            boxes: vec![],
+            indices: vec![],
+            level_bounds: vec![],
+        };
+        if index.items.is_empty() {
+            return Ok(index);
+        }
+
+        // the number of nodes at each level, leaves first
+        let mut n = index.items.len();
+        let mut num_nodes = n;
+        index.level_bounds.push(n);
+        loop {
+            n = n.div_ceil(node_size);
+            num_nodes += n;
+            index.level_bounds.push(num_nodes);
+            if n == 1 {
+                break;
+            }
+        }
+
+        let item_bbox = |item: &T| BBox {
+            min_x: item.get_min_x(),
+            min_y: item.get_min_y(),
+            max_x: item.get_max_x(),
+            max_y: item.get_max_y(),
+        };
+        let extent = index
+            .items
+            .iter()
+            .map(item_bbox)
+            .fold(item_bbox(&index.items[0]), |a, b| union(&a, &b));
+
+        // sort the items by the hilbert value of their centers
+        let to_f64 = |v: T::CoordType| v.to_f64().unwrap();
+        let width = to_f64(extent.max_x) - to_f64(extent.min_x);
+        let height = to_f64(extent.max_y) - to_f64(extent.min_y);
+        let hilbert_max = f64::from(u16::MAX);
+        let hilbert_values: Vec<u32> = index
+            .items
+            .iter()
+            .map(|item| {
+                let cx = (to_f64(item.get_min_x()) + to_f64(item.get_max_x())) / 2.0;
+                let cy = (to_f64(item.get_min_y()) + to_f64(item.get_max_y())) / 2.0;
+                let x = scale(cx - to_f64(extent.min_x), width, hilbert_max);
+                let y = scale(cy - to_f64(extent.min_y), height, hilbert_max);
+                hilbert(x, y)
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..index.items.len()).collect();
+        order.sort_by_key(|&i| hilbert_values[i]);
+
+        index.boxes = order.iter().map(|&i| item_bbox(&index.items[i])).collect();
+        index.indices = order;
+
+        // generate the nodes of each level from the one below it
+        let mut pos = 0;
+        for &end in &index.level_bounds[..index.level_bounds.len() - 1] {
+            while pos < end {
+                let node_index = pos;
+                let mut bbox = index.boxes[pos];
+                pos += 1;
+                while pos < end && pos - node_index < node_size {
+                    bbox = union(&bbox, &index.boxes[pos]);
+                    pos += 1;
+                }
+                index.boxes.push(bbox);
+                index.indices.push(node_index);
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Collects the ids of all items whose bounds intersect the query box.
+    pub fn search(
+        &self,
+        min_x: T::CoordType,
+        min_y: T::CoordType,
+        max_x: T::CoordType,
+        max_y: T::CoordType,
+        result: &mut Vec<usize>,
+    ) {
+        if self.boxes.is_empty() {
+            return;
+        }
+
+        let mut queue = vec![];
+        let mut node_index = self.boxes.len() - 1;
+        loop {
+            let end = usize::min(node_index + self.node_size, self.upper_bound(node_index));
+            for pos in node_index..end {
+                let b = &self.boxes[pos];
+                if max_x < b.min_x || max_y < b.min_y || min_x > b.max_x || min_y > b.max_y {
+                    continue;
+                }
+                if node_index < self.items.len() {
+                    result.push(self.indices[pos]);
+                } else {
+                    queue.push(self.indices[pos]);
+                }
+            }
+            match queue.pop() {
+                Some(next) => node_index = next,
+                None => return,
+            }
+        }
+    }
+
+    /// Collects the ids of all items whose bounds contain the point.
+    pub fn search_point(&self, x: T::CoordType, y: T::CoordType, result: &mut Vec<usize>) {
+        self.search(x, y, x, y, result)
+    }
+
+    /// Returns the ids of the items closest to the point, nearest first. The
+    /// distance to an item is the distance to the nearest point of its bounds.
+    pub fn neighbors(
+        &self,
+        x: T::CoordType,
+        y: T::CoordType,
+        max_results: Option<usize>,
+        max_distance: Option<T::CoordType>,
+    ) -> Vec<usize>
+    where
+        T::CoordType: Float,
+    {
+        let mut result = vec![];
+        if self.boxes.is_empty() || max_results == Some(0) {
+            return result;
+        }
+
+        let max_dist_sq = max_distance.map(|d| d * d);
+        let mut q = BinaryHeap::new();
+        let mut node_index = self.boxes.len() - 1;
+        loop {
+            let end = usize::min(node_index + self.node_size, self.upper_bound(node_index));
+            for pos in node_index..end {
+                q.push(Candidate {
+                    id: self.indices[pos],
+                    leaf: node_index < self.items.len(),
+                    dist: box_sq_dist(x, y, &self.boxes[pos]),
+                });
+            }
+
+            while let Some(candidate) = q.peek() {
+                if !candidate.leaf {
+                    break;
+                }
+                if max_dist_sq.is_some_and(|d| candidate.dist > d) {
+                    return result;
+                }
+                result.push(candidate.id);
+                q.pop();
+                if max_results.is_some_and(|n| result.len() == n) {
+                    return result;
+                }
+            }
+
+            match q.pop() {
+                Some(Candidate { dist, .. }) if max_dist_sq.is_some_and(|d| dist > d) => {
+                    return result
+                }
+                Some(candidate) => node_index = candidate.id,
+                None => return result,
+            }
+        }
+    }
+
+    // end of the level `node_index` is on
+    fn upper_bound(&self, node_index: usize) -> usize {
+        *self
+            .level_bounds
+            .iter()
+            .find(|&&bound| bound > node_index)
+            .unwrap()
+    }
+}
+
+// a node or item on the neighbors queue, closest first
+struct Candidate<T> {
+    id: usize,
+    leaf: bool,
+    dist: T,
+}
+
+impl<T: PartialOrd> PartialEq for Candidate<T> {
+    fn eq(&self, other: &Candidate<T>) -> bool {
+        self.dist == other.dist
+    }
+}
+
+impl<T: PartialOrd> Eq for Candidate<T> {}
+
+impl<T: PartialOrd> Ord for Candidate<T> {
+    fn cmp(&self, other: &Candidate<T>) -> Ordering {
+        other
+            .dist
+            .partial_cmp(&self.dist)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl<T: PartialOrd> PartialOrd for Candidate<T> {
+    fn partial_cmp(&self, other: &Candidate<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn union<T: PartialOrd + Copy>(a: &BBox<T>, b: &BBox<T>) -> BBox<T> {
+    let min = |a: T, b: T| if b < a { b } else { a };
+    let max = |a: T, b: T| if b > a { b } else { a };
+    BBox {
+        min_x: min(a.min_x, b.min_x),
+        min_y: min(a.min_y, b.min_y),
+        max_x: max(a.max_x, b.max_x),
+        max_y: max(a.max_y, b.max_y),
+    }
+}
+
+fn box_sq_dist<T: Float>(x: T, y: T, b: &BBox<T>) -> T {
+    let zero = T::zero();
+    let dx = T::max(T::max(b.min_x - x, zero), x - b.max_x);
+    let dy = T::max(T::max(b.min_y - y, zero), y - b.max_y);
+    dx * dx + dy * dy
+}
+
+fn scale(v: f64, size: f64, max: f64) -> u32 {
+    if size > 0.0 {
+        (max * v / size).floor() as u32
+    } else {
+        0
+    }
+}
+
+// Fast Hilbert curve algorithm by http://threadlocalmutex.com/
+// Ported from C++ https://github.com/rawrunprotected/hilbert_curves (public domain)
+fn hilbert(x: u32, y: u32) -> u32 {
+    let mut a = x ^ y;
+    let mut b = 0xFFFF ^ a;
+    let mut c = 0xFFFF ^ (x | y);
+    let mut d = x & (y ^ 0xFFFF);
+
+    let mut aa = a | (b >> 1);
+    let mut bb = (a >> 1) ^ a;
+    let mut cc = ((c >> 1) ^ (b & (d >> 1))) ^ c;
+    let mut dd = ((a & (c >> 1)) ^ (d >> 1)) ^ d;
+
+    a = aa;
+    b = bb;
+    c = cc;
+    d = dd;
+    aa = (a & (a >> 2)) ^ (b & (b >> 2));
+    bb = (a & (b >> 2)) ^ (b & ((a ^ b) >> 2));
+    cc ^= (a & (c >> 2)) ^ (b & (d >> 2));
+    dd ^= (b & (c >> 2)) ^ ((a ^ b) & (d >> 2));
+
+    a = aa;
+    b = bb;
+    c = cc;
+    d = dd;
+    aa = (a & (a >> 4)) ^ (b & (b >> 4));
+    bb = (a & (b >> 4)) ^ (b & ((a ^ b) >> 4));
+    cc ^= (a & (c >> 4)) ^ (b & (d >> 4));
+    dd ^= (b & (c >> 4)) ^ ((a ^ b) & (d >> 4));
+
+    a = aa;
+    b = bb;
+    c = cc;
+    d = dd;
+    cc ^= (a & (c >> 8)) ^ (b & (d >> 8));
+    dd ^= (b & (c >> 8)) ^ ((a ^ b) & (d >> 8));
+
+    a = cc ^ (cc >> 1);
+    b = dd ^ (dd >> 1);
+
+    let mut i0 = x ^ y;
+    let mut i1 = b | (0xFFFF ^ (i0 | a));
+
+    i0 = (i0 | (i0 << 8)) & 0x00FF_00FF;
+    i0 = (i0 | (i0 << 4)) & 0x0F0F_0F0F;
+    i0 = (i0 | (i0 << 2)) & 0x3333_3333;
+    i0 = (i0 | (i0 << 1)) & 0x5555_5555;
+
+    i1 = (i1 | (i1 << 8)) & 0x00FF_00FF;
+    i1 = (i1 | (i1 << 4)) & 0x0F0F_0F0F;
+    i1 = (i1 | (i1 << 2)) & 0x3333_3333;
+    i1 = (i1 | (i1 << 1)) & 0x5555_5555;
+
+    (i1 << 1) | i0
+}
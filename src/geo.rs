@@ -0,0 +1,118 @@
+//! Geographic helpers that do not need an index.
+
+use num::{Float, NumCast};
+use num_traits::FloatConst;
+
+pub mod geodesy;
+
+// mean radius of the Earth in km
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// Unit of the distances taken and returned by the geo queries. A custom
+/// `SphereRadius` measures distances on a sphere of that radius instead.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Units {
+    #[default]
+    Kilometers,
+    Meters,
+    Miles,
+    NauticalMiles,
+    SphereRadius(f64),
+}
+
+impl Units {
+    /// Radius of the sphere in these units.
+    pub fn earth_radius<T: Float>(self) -> T {
+        NumCast::from(EARTH_RADIUS_KM * self.per_km()).unwrap()
+    }
+
+    // length of a km in these units, for a custom sphere the ratio of its
+    // radius to that of the Earth
+    pub(crate) fn per_km(self) -> f64 {
+        match self {
+            Units::Kilometers => 1.0,
+            Units::Meters => 1000.0,
+            Units::Miles => 1.0 / 1.609_344,
+            Units::NauticalMiles => 1.0 / 1.852,
+            Units::SphereRadius(radius) => radius / EARTH_RADIUS_KM,
+        }
+    }
+}
+
+pub(crate) fn rad<T: Float + FloatConst>() -> T
+where
+    T: std::ops::Div<Output = T>,
+{
+    T::PI() / NumCast::from(180.0).unwrap()
+}
+
+// longitude, or a difference of longitudes, moved into [-180, 180)
+pub(crate) fn normalize_lng<T: Float>(lng: T) -> T {
+    let one80: T = NumCast::from(180.0).unwrap();
+    let three60: T = NumCast::from(360.0).unwrap();
+    let lng = (lng + one80) % three60;
+    if lng < T::zero() {
+        lng + three60 - one80
+    } else {
+        lng - one80
+    }
+}
+
+// Signed angles in radians from the great circle through `a` with unit
+// normal `n` to `p`, positive to the right of the direction of travel, and
+// along it from `a` to the point closest to `p`, negative behind `a`. The
+// closest point is returned too, not of unit length.
+pub(crate) fn track<T: Float>(p: &[T; 3], a: &[T; 3], n: &[T; 3]) -> (T, T, [T; 3]) {
+    let offset = dot(p, n);
+    let proj = [
+        p[0] - offset * n[0],
+        p[1] - offset * n[1],
+        p[2] - offset * n[2],
+    ];
+    let cross_track = -offset.max(-T::one()).min(T::one()).asin();
+    let along = dot(&cross(a, &proj), n).atan2(dot(a, &proj));
+    (cross_track, along, proj)
+}
+
+// unit normal of the great circle from `a` to `b`, zero if they coincide
+pub(crate) fn unit_normal<T: Float>(a: &[T; 3], b: &[T; 3]) -> [T; 3] {
+    let n = cross(a, b);
+    let norm = dot(&n, &n).sqrt();
+    if norm > T::epsilon() {
+        [n[0] / norm, n[1] / norm, n[2] / norm]
+    } else {
+        [T::zero(); 3]
+    }
+}
+
+// angle between two vectors, accurate for small angles unlike acos
+pub(crate) fn angle_between<T: Float>(a: &[T; 3], b: &[T; 3]) -> T {
+    let c = cross(a, b);
+    dot(&c, &c).sqrt().atan2(dot(a, b))
+}
+
+// longitude and latitude of a vector that need not be of unit length
+pub(crate) fn lng_lat<T: Float + FloatConst>(v: &[T; 3]) -> (T, T) {
+    (
+        v[1].atan2(v[0]) / rad::<T>(),
+        v[2].atan2(v[0].hypot(v[1])) / rad::<T>(),
+    )
+}
+
+pub(crate) fn unit_vector<T: Float + FloatConst>(lng: T, lat: T) -> [T; 3] {
+    let (sin_lng, cos_lng) = (lng * rad::<T>()).sin_cos();
+    let (sin_lat, cos_lat) = (lat * rad::<T>()).sin_cos();
+    [cos_lat * cos_lng, cos_lat * sin_lng, sin_lat]
+}
+
+pub(crate) fn cross<T: Float>(a: &[T; 3], b: &[T; 3]) -> [T; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+pub(crate) fn dot<T: Float>(a: &[T; 3], b: &[T; 3]) -> T {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
@@ -0,0 +1,157 @@
+//! Calculations on the sphere with the mean radius of the Earth, after
+//! Chris Veness' movable-type.co.uk scripts. Coordinates and bearings are in
+//! degrees, bearings clockwise from north, distances in the given `Units`.
+
+use num::{Float, NumCast};
+use num_traits::FloatConst;
+
+use crate::geo::{angle_between, normalize_lng, rad, track, unit_normal, unit_vector, Units};
+
+/// Initial bearing of the great circle from the first point to the second,
+/// in [0, 360).
+pub fn bearing<T>(lng: T, lat: T, lng2: T, lat2: T) -> T
+where
+    T: Float + FloatConst,
+{
+    let (lat, lat2) = (lat * rad::<T>(), lat2 * rad::<T>());
+    let d_lng = (lng2 - lng) * rad::<T>();
+    let y = d_lng.sin() * lat2.cos();
+    let x = lat.cos() * lat2.sin() - lat.sin() * lat2.cos() * d_lng.cos();
+    let three60: T = NumCast::from(360.0).unwrap();
+    (y.atan2(x) / rad::<T>() + three60) % three60
+}
+
+/// The point reached after travelling `dist` along the great circle that
+/// starts at the point with the given initial `bearing`.
+pub fn destination<T>(lng: T, lat: T, dist: T, bearing: T, units: Units) -> (T, T)
+where
+    T: Float + FloatConst,
+{
+    let delta = dist / units.earth_radius();
+    let theta = bearing * rad::<T>();
+    let (lng, lat) = (lng * rad::<T>(), lat * rad::<T>());
+    let lat2 = (lat.sin() * delta.cos() + lat.cos() * delta.sin() * theta.cos()).asin();
+    let lng2 =
+        lng + (theta.sin() * delta.sin() * lat.cos()).atan2(delta.cos() - lat.sin() * lat2.sin());
+    (normalize_lng(lng2 / rad::<T>()), lat2 / rad::<T>())
+}
+
+/// The point halfway along the great circle between two points.
+pub fn midpoint<T>(lng: T, lat: T, lng2: T, lat2: T) -> (T, T)
+where
+    T: Float + FloatConst,
+{
+    let (lng, lat, lat2) = (lng * rad::<T>(), lat * rad::<T>(), lat2 * rad::<T>());
+    let d_lng = lng2 * rad::<T>() - lng;
+    let bx = lat2.cos() * d_lng.cos();
+    let by = lat2.cos() * d_lng.sin();
+    let lat_m = (lat.sin() + lat2.sin()).atan2((lat.cos() + bx).hypot(by));
+    let lng_m = lng + by.atan2(lat.cos() + bx);
+    (normalize_lng(lng_m / rad::<T>()), lat_m / rad::<T>())
+}
+
+/// The point at `fraction` of the way along the great circle between two
+/// points, 0 being the first and 1 the second.
+pub fn interpolate<T>(lng: T, lat: T, lng2: T, lat2: T, fraction: T) -> (T, T)
+where
+    T: Float + FloatConst,
+{
+    let delta = angular_distance(lng, lat, lng2, lat2);
+    if delta == T::zero() {
+        return (lng, lat);
+    }
+    let a = ((T::one() - fraction) * delta).sin() / delta.sin();
+    let b = (fraction * delta).sin() / delta.sin();
+    let (lng, lat, lng2, lat2) = (
+        lng * rad::<T>(),
+        lat * rad::<T>(),
+        lng2 * rad::<T>(),
+        lat2 * rad::<T>(),
+    );
+    let x = a * lat.cos() * lng.cos() + b * lat2.cos() * lng2.cos();
+    let y = a * lat.cos() * lng.sin() + b * lat2.cos() * lng2.sin();
+    let z = a * lat.sin() + b * lat2.sin();
+    (y.atan2(x) / rad::<T>(), z.atan2(x.hypot(y)) / rad::<T>())
+}
+
+/// Signed distance from a point to the great circle through `start` and
+/// `end`, positive to the right of the direction of travel.
+#[allow(clippy::too_many_arguments)]
+pub fn cross_track_distance<T>(
+    lng: T,
+    lat: T,
+    start_lng: T,
+    start_lat: T,
+    end_lng: T,
+    end_lat: T,
+    units: Units,
+) -> T
+where
+    T: Float + FloatConst,
+{
+    let (cross_track, _) = track_angles(lng, lat, start_lng, start_lat, end_lng, end_lat);
+    cross_track * units.earth_radius()
+}
+
+/// Distance from `start` to the point on the great circle through `start`
+/// and `end` closest to a point, negative if it lies behind `start`.
+#[allow(clippy::too_many_arguments)]
+pub fn along_track_distance<T>(
+    lng: T,
+    lat: T,
+    start_lng: T,
+    start_lat: T,
+    end_lng: T,
+    end_lat: T,
+    units: Units,
+) -> T
+where
+    T: Float + FloatConst,
+{
+    let (_, along) = track_angles(lng, lat, start_lng, start_lat, end_lng, end_lat);
+    along * units.earth_radius()
+}
+
+/// Distance along the rhumb line, the path of constant bearing, between two
+/// points. Never shorter than the great circle distance.
+pub fn rhumb_distance<T>(lng: T, lat: T, lng2: T, lat2: T, units: Units) -> T
+where
+    T: Float + FloatConst,
+{
+    let two = T::one() + T::one();
+    let (lat, lat2) = (lat * rad::<T>(), lat2 * rad::<T>());
+    let d_lat = lat2 - lat;
+    let mut d_lng = ((lng2 - lng) * rad::<T>()).abs();
+    if d_lng > T::PI() {
+        d_lng = T::PI() + T::PI() - d_lng;
+    }
+    // stretched latitude difference on a Mercator projection
+    let d_psi = ((T::FRAC_PI_4() + lat2 / two).tan() / (T::FRAC_PI_4() + lat / two).tan()).ln();
+    // east-west lines have no stretch, use the cosine of the latitude instead
+    let q = if d_psi.abs() > NumCast::from(1e-12).unwrap() {
+        d_lat / d_psi
+    } else {
+        lat.cos()
+    };
+    d_lat.hypot(q * d_lng) * units.earth_radius()
+}
+
+// great circle distance in radians
+fn angular_distance<T>(lng: T, lat: T, lng2: T, lat2: T) -> T
+where
+    T: Float + FloatConst,
+{
+    angle_between(&unit_vector(lng, lat), &unit_vector(lng2, lat2))
+}
+
+// cross and along track angles of a point to the great circle from `start`
+// to `end`, shared with the segments of `geokdbush::along_route`
+fn track_angles<T>(lng: T, lat: T, start_lng: T, start_lat: T, end_lng: T, end_lat: T) -> (T, T)
+where
+    T: Float + FloatConst,
+{
+    let a = unit_vector(start_lng, start_lat);
+    let n = unit_normal(&a, &unit_vector(end_lng, end_lat));
+    let (cross_track, along, _) = track(&unit_vector(lng, lat), &a, &n);
+    (cross_track, along)
+}
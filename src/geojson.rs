@@ -0,0 +1,141 @@
+//! Building an index from a GeoJSON FeatureCollection and exporting query
+//! results as one.
+
+use std::io::{Error, ErrorKind, Read};
+
+use num::{Float, ToPrimitive};
+use serde_json::{json, Map, Value};
+
+use crate::geokdbush::point_feature;
+use crate::kdbush::{City, Coords, KDBush, RawCoord};
+
+/// A point of a GeoJSON feature. Every position of a MultiPoint becomes a
+/// `Feature` of its own, sharing the id and properties.
+#[derive(Debug, Clone, PartialEq, Coords)]
+#[coords(x = "lng", y = "lat", crate = "crate")]
+pub struct Feature {
+    pub lng: f64,
+    pub lat: f64,
+    /// Position of the source feature in the collection.
+    pub feature: usize,
+    pub id: Option<Value>,
+    pub properties: Value,
+}
+
+/// Reads a FeatureCollection and indexes its points.
+pub fn read_feature_collection<R: Read>(
+    reader: R,
+    node_size: usize,
+) -> Result<KDBush<Feature>, Error> {
+    let collection: Value = serde_json::from_reader(reader)?;
+    KDBush::new(features(&collection)?, node_size)
+}
+
+/// The points of the Point and MultiPoint features of a FeatureCollection.
+/// Features with other or no geometry are skipped.
+pub fn features(collection: &Value) -> Result<Vec<Feature>, Error> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    if collection["type"] != "FeatureCollection" {
+        return Err(invalid("not a FeatureCollection".to_string()));
+    }
+    let list = collection["features"]
+        .as_array()
+        .ok_or_else(|| invalid("FeatureCollection without features".to_string()))?;
+
+    let mut result = vec![];
+    for (i, feature) in list.iter().enumerate() {
+        let geometry = &feature["geometry"];
+        let coordinates = &geometry["coordinates"];
+        let positions = match geometry["type"].as_str() {
+            Some("Point") => vec![coordinates],
+            Some("MultiPoint") => coordinates
+                .as_array()
+                .ok_or_else(|| invalid(format!("feature {}: invalid MultiPoint", i)))?
+                .iter()
+                .collect(),
+            _ => continue,
+        };
+        for position in positions {
+            let (lng, lat) = match position.as_array().map(Vec::as_slice) {
+                Some([lng, lat, ..]) => (lng.as_f64(), lat.as_f64()),
+                _ => (None, None),
+            };
+            let (lng, lat) = lng
+                .zip(lat)
+                .ok_or_else(|| invalid(format!("feature {}: invalid position {}", i, position)))?;
+            result.push(Feature {
+                lng,
+                lat,
+                feature: i,
+                id: feature.get("id").cloned(),
+                properties: feature["properties"].clone(),
+            });
+        }
+    }
+    Ok(result)
+}
+
+/// Items that can be written out as GeoJSON features. The defaults write
+/// no id and no properties.
+pub trait ToFeature: Coords {
+    fn id(&self) -> Option<Value> {
+        None
+    }
+
+    fn properties(&self) -> Map<String, Value> {
+        Map::new()
+    }
+}
+
+impl ToFeature for Feature {
+    fn id(&self) -> Option<Value> {
+        self.id.clone()
+    }
+
+    fn properties(&self) -> Map<String, Value> {
+        self.properties.as_object().cloned().unwrap_or_default()
+    }
+}
+
+impl<T: Float> ToFeature for RawCoord<T> {}
+
+impl ToFeature for City {
+    fn properties(&self) -> Map<String, Value> {
+        let mut properties = Map::new();
+        properties.insert("name".to_string(), json!(self.name));
+        properties.insert("country".to_string(), json!(self.country));
+        properties.insert("adminCode".to_string(), json!(self.admin_code));
+        properties.insert("population".to_string(), json!(self.population));
+        properties
+    }
+}
+
+/// A FeatureCollection of query results, given as ids in `index.points` with
+/// their distance if the query has one. Every feature gets the properties of
+/// its item plus `kdbush:index`, the id, and `kdbush:distance`. The keys are
+/// namespaced so they never replace a property of the item itself, whose own
+/// id, if any, is the `id` of the feature.
+pub fn results_to_geojson<T, I>(index: &KDBush<T>, results: I) -> Value
+where
+    T: ToFeature,
+    T::CoordType: Float,
+    I: IntoIterator<Item = (usize, Option<T::CoordType>)>,
+{
+    let features: Vec<Value> = results
+        .into_iter()
+        .map(|(id, distance)| {
+            let item = &index.points[id];
+            let mut properties = item.properties();
+            properties.insert("kdbush:index".to_string(), json!(id));
+            if let Some(distance) = distance {
+                properties.insert("kdbush:distance".to_string(), json!(distance.to_f64()));
+            }
+            let mut feature = point_feature(item.get_x(), item.get_y(), Value::Object(properties));
+            if let Some(id) = item.id() {
+                feature["id"] = id;
+            }
+            feature
+        })
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features })
+}
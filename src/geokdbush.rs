@@ -7,44 +7,111 @@ extern crate serde_json;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::fmt;
+use std::ops::AddAssign;
 
-use crate::kdbush::{Coords, KDBush};
-use num::{Float, NumCast};
+pub use crate::geo::Units;
+use crate::geo::{
+    angle_between, cross, dot, lng_lat, normalize_lng, rad, track, unit_normal, unit_vector,
+};
+use crate::kdbush::{BBox, Coords, KDBush, ScoreBounds, ValidationError};
+use num::{Float, NumCast, ToPrimitive};
 use num_traits::FloatConst;
+use serde_json::{json, Value};
 
-fn earth_radius<T: Float + FloatConst>() -> T
-where
-    T: std::ops::Div<Output = T>,
-{
-    NumCast::from(6137.0).unwrap()
+// WGS84 semi-major axis in km and flattening
+const WGS84_A_KM: f64 = 6378.137;
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
+
+/// The model of the Earth distances are measured on. The default is still
+/// `Spherical`, so queries that do not pass a formula keep their results;
+/// pass `Haversine` where points a few metres apart have to be told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Formula {
+    /// Great circle distance on a sphere with the mean radius of the Earth,
+    /// using the spherical law of cosines. Fast, but `acos` loses precision
+    /// for points closer than a few metres, which can tie or reorder them.
+    #[default]
+    Spherical,
+    /// The same great circle distance using the haversine formula with
+    /// `atan2`, which stays accurate down to sub-millimetre separations.
+    Haversine,
+    /// Geodesic distance on the WGS84 ellipsoid, see `distance_ellipsoidal`.
+    Ellipsoidal,
 }
 
-fn earth_circumference<T: Float + FloatConst>() -> T
+struct Node<T>
 where
-    T: std::ops::Div<Output = T>,
+    T: Float + FloatConst + PartialOrd,
 {
-    NumCast::from(40007.0).unwrap()
+    left: usize,
+    right: usize,
+    axis: u8,
+    // dist: f64,
+    bbox: BBox<T>, // x is longitude, y is latitude
 }
 
-fn rad<T: Float + FloatConst>() -> T
+impl<T> Node<T>
 where
-    T: std::ops::Div<Output = T>,
+    T: Float + FloatConst + PartialOrd,
 {
-    T::PI() / NumCast::from(180.0).unwrap()
+    // the top kd-tree node, the whole Earth
+    fn root<P: Coords<CoordType = T>>(index: &KDBush<P>) -> Node<T> {
+        Node {
+            left: 0,
+            right: index.ids.len() - 1,
+            axis: 0, // 0 for longitude axis and 1 for latitude axis
+            bbox: world_bbox(),
+        }
+    }
+
+    // the position of the median of a node that is not a leaf, and the two
+    // children it splits the node into
+    fn split<P: Coords<CoordType = T>>(&self, index: &KDBush<P>) -> (usize, [Node<T>; 2]) {
+        let m = (self.left + self.right) >> 1;
+        let p = &index.points[index.ids[m]];
+        let (left_bbox, right_bbox) = self.bbox.split(self.axis, p.get_x(), p.get_y());
+        let axis = (self.axis + 1) % 2;
+        let left = Node {
+            left: self.left,
+            right: m - 1,
+            axis,
+            bbox: left_bbox,
+        };
+        let right = Node {
+            left: m + 1,
+            right: self.right,
+            axis,
+            bbox: right_bbox,
+        };
+        (m, [left, right])
+    }
 }
 
-struct Node<T>
+// Depth first walk over the kd nodes for which `visit` returns true, calling
+// `test` with the position in `ids` of every point in them.
+fn walk<T, V, F>(index: &KDBush<T>, mut visit: V, mut test: F)
 where
-    T: Float + FloatConst + PartialOrd,
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+    V: FnMut(&Node<T::CoordType>) -> bool,
+    F: FnMut(usize),
 {
-    left: usize,
-    right: usize,
-    axis: u8,
-    // dist: f64,
-    min_lng: T,
-    min_lat: T,
-    max_lng: T,
-    max_lat: T,
+    if index.ids.is_empty() {
+        return;
+    }
+    let mut stack = vec![Node::root(index)];
+    while let Some(node) = stack.pop() {
+        if !visit(&node) {
+            continue;
+        }
+        if node.right - node.left <= index.node_size {
+            (node.left..node.right + 1).for_each(&mut test);
+            continue;
+        }
+        let (m, children) = node.split(index);
+        test(m);
+        stack.extend(children);
+    }
 }
 
 // type Dist = f64;
@@ -53,7 +120,7 @@ where
     T: Coords,
     T::CoordType: Float + FloatConst + PartialOrd,
 {
-    Point(&'a T),
+    Point(usize, &'a T),
     Node(Node<<T as Coords>::CoordType>),
 }
 
@@ -65,7 +132,12 @@ where
         write!(
             formatter,
             "({},{},{},{},{},{})",
-            self.left, self.right, self.min_lat, self.min_lng, self.max_lat, self.max_lng
+            self.left,
+            self.right,
+            self.bbox.min_y,
+            self.bbox.min_x,
+            self.bbox.max_y,
+            self.bbox.max_x
         )
     }
 }
@@ -110,231 +182,1246 @@ where
     }
 }
 
+/// Optional filter on the items returned by `around`.
+pub type Predicate<T> = Option<Box<dyn Fn(&T) -> bool>>;
+
+/// Counters of the work done by a nearest neighbour search. Stats of several
+/// queries can be summed with `+=`, which keeps the largest `heap_peak`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueryStats {
+    /// kd nodes taken off the queue and expanded
+    pub nodes_visited: usize,
+    /// points whose distance to the query location was computed
+    pub points_tested: usize,
+    /// largest number of points and nodes queued at once
+    pub heap_peak: usize,
+    /// distances computed, to points as well as to node bounding boxes
+    pub distance_evals: usize,
+}
+
+impl AddAssign for QueryStats {
+    fn add_assign(&mut self, other: QueryStats) {
+        self.nodes_visited += other.nodes_visited;
+        self.points_tested += other.points_tested;
+        self.heap_peak = usize::max(self.heap_peak, other.heap_peak);
+        self.distance_evals += other.distance_evals;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn around<'a, T>(
     index: &'a KDBush<T>,
     lng: T::CoordType,
     lat: T::CoordType,
     max_results: Option<usize>,
     max_distance: Option<T::CoordType>,
-    predicate: &Option<Box<Fn(&T) -> bool>>,
+    predicate: &Predicate<T>,
+    units: Units,
+    formula: Formula,
 ) -> Vec<&'a T>
 where
-    T: fmt::Debug + Coords,
-    T::CoordType: Float + PartialOrd + FloatConst + fmt::Debug + fmt::Display,
+    T: Coords,
+    T::CoordType: Float + PartialOrd + FloatConst,
+{
+    traverse(
+        index,
+        lng,
+        lat,
+        max_results,
+        max_distance,
+        predicate,
+        units,
+        formula,
+        |_| {},
+    )
+    .0
+    .into_iter()
+    .map(|(_, item, _)| item)
+    .collect()
+}
+
+/// Like `around`, but returns the id, the item and its distance in `units`
+/// for every result.
+#[allow(clippy::too_many_arguments)]
+pub fn around_with_distance<'a, T>(
+    index: &'a KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    max_results: Option<usize>,
+    max_distance: Option<T::CoordType>,
+    predicate: &Predicate<T>,
+    units: Units,
+    formula: Formula,
+) -> Vec<(usize, &'a T, T::CoordType)>
+where
+    T: Coords,
+    T::CoordType: Float + PartialOrd + FloatConst,
+{
+    traverse(
+        index,
+        lng,
+        lat,
+        max_results,
+        max_distance,
+        predicate,
+        units,
+        formula,
+        |_| {},
+    )
+    .0
+}
+
+// the `(id, item, distance)` results of a nearest neighbour search
+type Neighbours<'a, T> = Vec<(usize, &'a T, <T as Coords>::CoordType)>;
+
+/// Like `around_with_distance`, but also returns the work the search did.
+#[allow(clippy::too_many_arguments)]
+pub fn around_with_stats<'a, T>(
+    index: &'a KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    max_results: Option<usize>,
+    max_distance: Option<T::CoordType>,
+    predicate: &Predicate<T>,
+    units: Units,
+    formula: Formula,
+) -> (Neighbours<'a, T>, QueryStats)
+where
+    T: Coords,
+    T::CoordType: Float + PartialOrd + FloatConst,
+{
+    traverse(
+        index,
+        lng,
+        lat,
+        max_results,
+        max_distance,
+        predicate,
+        units,
+        formula,
+        |_| {},
+    )
+}
+
+/// A nearest neighbour query built up step by step, the builder counterpart
+/// of `around`:
+///
+/// ```ignore
+/// let big = GeoQuery::new(-119.7, 34.4)
+///     .limit(5)
+///     .max_distance(100.0)
+///     .filter(|c: &City| c.population > min_population)
+///     .run(&index);
+/// ```
+///
+/// The filter can be any `FnMut`, so it may borrow or update local state.
+#[derive(Debug, Clone)]
+pub struct GeoQuery<C, F = NoFilter> {
+    lng: C,
+    lat: C,
+    limit: Option<usize>,
+    max_distance: Option<C>,
+    units: Units,
+    formula: Formula,
+    filter: F,
+}
+
+/// The filter of a `GeoQuery` that accepts every item.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFilter;
+
+/// Filters the items of a `GeoQuery`, implemented for every `FnMut(&T) -> bool`.
+pub trait QueryFilter<T> {
+    fn accept(&mut self, item: &T) -> bool;
+}
+
+impl<T> QueryFilter<T> for NoFilter {
+    fn accept(&mut self, _: &T) -> bool {
+        true
+    }
+}
+
+impl<T, F> QueryFilter<T> for F
+where
+    F: FnMut(&T) -> bool,
+{
+    fn accept(&mut self, item: &T) -> bool {
+        self(item)
+    }
+}
+
+impl<C> GeoQuery<C>
+where
+    C: Float + FloatConst,
+{
+    pub fn new(lng: C, lat: C) -> GeoQuery<C> {
+        GeoQuery {
+            lng,
+            lat,
+            limit: None,
+            max_distance: None,
+            units: Units::default(),
+            formula: Formula::default(),
+            filter: NoFilter,
+        }
+    }
+}
+
+impl<C, F> GeoQuery<C, F>
+where
+    C: Float + FloatConst,
+{
+    /// Returns at most `n` results.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Returns only results within `distance`, in the query's `units`.
+    pub fn max_distance(mut self, distance: C) -> Self {
+        self.max_distance = Some(distance);
+        self
+    }
+
+    pub fn units(mut self, units: Units) -> Self {
+        self.units = units;
+        self
+    }
+
+    pub fn formula(mut self, formula: Formula) -> Self {
+        self.formula = formula;
+        self
+    }
+
+    /// Only returns the items for which `filter` returns true. Replaces any
+    /// earlier filter.
+    pub fn filter<G>(self, filter: G) -> GeoQuery<C, G> {
+        GeoQuery {
+            lng: self.lng,
+            lat: self.lat,
+            limit: self.limit,
+            max_distance: self.max_distance,
+            units: self.units,
+            formula: self.formula,
+            filter,
+        }
+    }
+
+    /// Lazily yields `(id, item, distance)` for the matching items, nearest
+    /// first. The items borrow the index for `'a`, the iterator only has to
+    /// live as long as the filter.
+    pub fn iter<'a, 'f, T>(
+        self,
+        index: &'a KDBush<T>,
+    ) -> impl Iterator<Item = (usize, &'a T, C)> + 'f
+    where
+        T: Coords<CoordType = C>,
+        F: QueryFilter<T> + 'f,
+        C: 'f,
+        'a: 'f,
+    {
+        let GeoQuery {
+            lng,
+            lat,
+            limit,
+            max_distance,
+            units,
+            formula,
+            mut filter,
+        } = self;
+        nearest_iter(index, lng, lat)
+            .units(units)
+            .formula(formula)
+            .take_while(move |&(_, _, dist)| max_distance.is_none_or(|max| dist <= max))
+            .filter(move |&(_, item, _)| filter.accept(item))
+            .take(limit.unwrap_or(usize::MAX))
+    }
+
+    /// Collects `(id, item, distance)` for the matching items, nearest first.
+    pub fn run<T>(self, index: &KDBush<T>) -> Vec<(usize, &T, C)>
+    where
+        T: Coords<CoordType = C>,
+        F: QueryFilter<T>,
+    {
+        self.iter(index).collect()
+    }
+
+    /// The number of matching items.
+    pub fn count<T>(self, index: &KDBush<T>) -> usize
+    where
+        T: Coords<CoordType = C>,
+        F: QueryFilter<T>,
+    {
+        self.iter(index).count()
+    }
+}
+
+// The search behind `around`, calling `visit` for every kd node in the order
+// it is taken off the queue.
+#[allow(clippy::too_many_arguments)]
+fn traverse<'a, T, V>(
+    index: &'a KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    max_results: Option<usize>,
+    max_distance: Option<T::CoordType>,
+    predicate: &Predicate<T>,
+    units: Units,
+    formula: Formula,
+    mut visit: V,
+) -> (Neighbours<'a, T>, QueryStats)
+where
+    T: Coords,
+    T::CoordType: Float + PartialOrd + FloatConst,
+    V: FnMut(&Node<T::CoordType>),
 {
     let mut result = vec![];
-    let cos_lat = T::CoordType::cos(lat * rad::<T::CoordType>());
-    let sin_lat = T::CoordType::sin(lat * rad::<T::CoordType>());
+    let mut iter = nearest_iter(index, lng, lat).units(units).formula(formula);
+    while max_results.is_none_or(|n| result.len() < n) {
+        let (id, point, dist) = match iter.next_with(&mut |node| {
+            visit(node);
+            true
+        }) {
+            Some(next) => next,
+            None => break,
+        };
+        if max_distance.is_some_and(|max| dist > max) {
+            break;
+        }
+        if predicate
+            .as_ref()
+            .is_some_and(|predicate| !predicate(point))
+        {
+            continue;
+        }
+        result.push((id, point, dist));
+    }
+    let stats = iter.stats();
+    report(&stats);
+    (result, stats)
+}
+
+// hands the stats of a finished query to the `log` or `tracing` feature
+#[allow(unused_variables)]
+fn report(stats: &QueryStats) {
+    #[cfg(feature = "log")]
+    log::trace!("geokdbush query: {:?}", stats);
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        nodes_visited = stats.nodes_visited,
+        points_tested = stats.points_tested,
+        heap_peak = stats.heap_peak,
+        distance_evals = stats.distance_evals,
+        "geokdbush query"
+    );
+}
+
+/// Lazily yields `(id, item, distance)` for every point of the index in
+/// increasing distance from a location, see `nearest_iter`.
+pub struct NearestIter<'a, T>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst + PartialOrd,
+{
+    index: &'a KDBush<T>,
+    origin: Origin<T::CoordType>,
+    units: Units,
+    formula: Formula,
+    q: BinaryHeap<PointDist<PointOrNode<'a, T>, T::CoordType>>,
+    stats: QueryStats,
+}
+
+/// Walks the index outwards from a location, yielding `(id, item, distance)`
+/// nearest first. Nothing is searched until the iterator is advanced, so
+/// `take`, `take_while` and `filter` stop the search as soon as they are
+/// satisfied. Distances are great circle kilometres unless changed with
+/// `units` and `formula` before the first call to `next`.
+pub fn nearest_iter<T>(
+    index: &KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+) -> NearestIter<'_, T>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst + PartialOrd,
+{
     let mut q = BinaryHeap::new();
+    if !index.ids.is_empty() {
+        q.push(PointDist(PointOrNode::Node(Node::root(index)), num::zero()));
+    }
+    NearestIter {
+        index,
+        origin: Origin::new(lng, lat),
+        units: Units::default(),
+        formula: Formula::default(),
+        q,
+        stats: QueryStats::default(),
+    }
+}
 
-    // an object that represents the top kd-tree node (the whole Earth)
-    let mut point_or_node = PointOrNode::Node(Node::<T::CoordType> {
-        left: 0,                                 // left index in the kd-tree array
-        right: index.ids.len() - 1,              // right index
-        axis: 0,                                 // 0 for longitude axis and 1 for latitude axis
-        min_lng: NumCast::from(-180.0).unwrap(), // bounding box of the node
-        min_lat: NumCast::from(-90.0).unwrap(),
-        max_lng: NumCast::from(180.0).unwrap(),
-        max_lat: NumCast::from(90.0).unwrap(),
-    });
+impl<'a, T> NearestIter<'a, T>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst + PartialOrd,
+{
+    pub fn units(mut self, units: Units) -> Self {
+        self.units = units;
+        self
+    }
 
-    'tree: loop {
-        let left;
-        let right;
-        if let PointOrNode::Node(node) = &point_or_node {
-            right = node.right;
-            left = node.left
-        } else {
-            panic!("No node in current enum.");
-        };
+    pub fn formula(mut self, formula: Formula) -> Self {
+        self.formula = formula;
+        self
+    }
 
-        println!("left:{:?},right: {:?}", left, right);
-        println!("node_size: {:?}", index.node_size);
-        if (right - left) <= index.node_size {
-            // leaf node
-            println!("fill heap");
-            (left..(right + 1)).for_each(|i: usize| {
-                let item = &index.points[index.ids[i]];
-                let predicate_check = match predicate {
-                    None => true,
-                    Some(predicate) => predicate(item),
-                };
-                let dist = great_circle_dist(
-                    lng,
-                    lat,
-                    // index.coords[i].get(0).into(),
-                    NumCast::from(item.get_x()).unwrap(),
-                    // index.coords[i].get(1).into(),
-                    NumCast::from(item.get_y()).unwrap(),
-                    cos_lat,
-                    sin_lat,
-                );
-                println!("leaf to heap {:?}", item);
-                println!("{:?}", dist);
-                if predicate_check {
-                    q.push(PointDist(PointOrNode::Point(item), dist));
-                }
-            })
-        } else {
-            // not a leaf node (has children). branch.
-            println!("branch node");
-            let m = (left + right) >> 1;
-            let mid_lng = index.points[index.ids[m]].get_x();
-            let mid_lat = index.points[index.ids[m]].get_y();
-
-            let item = &index.points[index.ids[m]];
-            let predicate_check = match predicate {
-                None => true,
-                Some(predicate) => predicate(item),
+    /// The work done so far.
+    pub fn stats(&self) -> QueryStats {
+        self.stats
+    }
+
+    fn point_dist(&mut self, lng: T::CoordType, lat: T::CoordType) -> T::CoordType {
+        self.stats.points_tested += 1;
+        self.stats.distance_evals += 1;
+        self.origin.dist(lng, lat, self.units, self.formula)
+    }
+
+    fn node_dist(&mut self, bbox: &BBox<T::CoordType>) -> T::CoordType {
+        self.stats.distance_evals += 1;
+        self.origin.box_bound(bbox, self.units, self.formula)
+    }
+
+    // `next`, calling `visit` for every kd node taken off the queue and
+    // skipping the nodes for which it returns false
+    fn next_with<V>(&mut self, visit: &mut V) -> Option<(usize, &'a T, T::CoordType)>
+    where
+        V: FnMut(&Node<T::CoordType>) -> bool,
+    {
+        let index = self.index;
+        loop {
+            let node = match self.q.pop()? {
+                PointDist(PointOrNode::Point(id, item), dist) => return Some((id, item, dist)),
+                PointDist(PointOrNode::Node(node), _) => node,
             };
-            if predicate_check {
-                let dist = great_circle_dist(lng, lat, mid_lng, mid_lat, cos_lat, sin_lat);
-                println!("branch to heap");
-                println!("{:?}", dist);
-                println!("{:?}", item);
-                q.push(PointDist(PointOrNode::Point(item), dist))
+            if !visit(&node) {
+                continue;
             }
+            self.stats.nodes_visited += 1;
+            let (left, right) = (node.left, node.right);
 
-            if let PointOrNode::Node(node) = point_or_node {
-                let next_axis = (node.axis + 1) % 2;
-
-                let left_node = Node::<<T as Coords>::CoordType> {
-                    left: left,
-                    right: m - 1,
-                    axis: next_axis,
-                    min_lng: NumCast::from(node.min_lng).unwrap(),
-                    min_lat: NumCast::from(node.min_lat).unwrap(),
-                    max_lng: if node.axis == 0 {
-                        NumCast::from(mid_lng).unwrap()
-                    } else {
-                        NumCast::from(node.max_lng).unwrap()
-                    },
-                    max_lat: if node.axis == 1 {
-                        NumCast::from(mid_lat).unwrap()
-                    } else {
-                        NumCast::from(node.max_lat).unwrap()
-                    },
-                    // dist: 0.0,
-                };
-
-                let right_node = Node::<<T as Coords>::CoordType> {
-                    left: m + 1,
-                    right: right,
-                    axis: next_axis,
-                    min_lng: if node.axis == 0 {
-                        NumCast::from(mid_lng).unwrap()
-                    } else {
-                        NumCast::from(node.min_lng).unwrap()
-                    },
-                    min_lat: if node.axis == 1 {
-                        NumCast::from(mid_lat).unwrap()
-                    } else {
-                        NumCast::from(node.min_lat).unwrap()
-                    },
-                    max_lng: NumCast::from(node.max_lng).unwrap(),
-                    max_lat: NumCast::from(node.max_lat).unwrap(),
-                    // dist: 0.0,
-                };
-
-                let left_node_dist = box_dist(lng, lat, Box::new(&left_node), cos_lat, sin_lat);
-                let right_node_dist = box_dist(lng, lat, Box::new(&right_node), cos_lat, sin_lat);
-                q.push(PointDist(PointOrNode::Node(left_node), left_node_dist));
-                q.push(PointDist(PointOrNode::Node(right_node), right_node_dist));
-                println!("{:?}", q.len());
+            if (right - left) <= index.node_size {
+                // leaf node
+                for i in left..=right {
+                    let item = &index.points[index.ids[i]];
+                    let dist = self.point_dist(item.get_x(), item.get_y());
+                    self.q
+                        .push(PointDist(PointOrNode::Point(index.ids[i], item), dist));
+                }
+            } else {
+                // not a leaf node (has children). branch.
+                let (m, [left_node, right_node]) = node.split(index);
+                let item = &index.points[index.ids[m]];
+                let dist = self.point_dist(item.get_x(), item.get_y());
+                self.q
+                    .push(PointDist(PointOrNode::Point(index.ids[m], item), dist));
+
+                let left_node_dist = self.node_dist(&left_node.bbox);
+                let right_node_dist = self.node_dist(&right_node.bbox);
+                self.q
+                    .push(PointDist(PointOrNode::Node(left_node), left_node_dist));
+                self.q
+                    .push(PointDist(PointOrNode::Node(right_node), right_node_dist));
             }
+            self.stats.heap_peak = usize::max(self.stats.heap_peak, self.q.len());
         }
+    }
+}
 
-        while q.len() > 0 && q.peek().is_some() {
-            if let PointOrNode::Point(_) = q.peek().unwrap().0 {
-                // a leaf node was found
-                let candidate = q.pop().unwrap();
-                if max_distance.is_some() && candidate.1 > max_distance.unwrap() {
-                    println!("max distance reached");
-                    return result;
-                }
-                if let PointOrNode::Point(point) = candidate.0 {
-                    println!("candidate");
-                    println!("point :\t{:?}", point);
-                    println!("dist :\t{:?}", candidate.1);
-                    result.push(point);
-                } else {
-                    println!("wut?");
-                }
+impl<'a, T> Iterator for NearestIter<'a, T>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst + PartialOrd,
+{
+    type Item = (usize, &'a T, T::CoordType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(&mut |_| true)
+    }
+}
+
+// A query location with the trigonometry shared by all distances from it.
+#[derive(Clone, Copy)]
+struct Origin<T> {
+    lng: T,
+    lat: T,
+    cos_lat: T,
+    sin_lat: T,
+}
+
+impl<T: Float + FloatConst> Origin<T> {
+    fn new(lng: T, lat: T) -> Origin<T> {
+        let (sin_lat, cos_lat) = (lat * rad::<T>()).sin_cos();
+        Origin {
+            lng,
+            lat,
+            cos_lat,
+            sin_lat,
+        }
+    }
+
+    // distance to a point in `units`, measured with `formula`
+    fn dist(&self, lng: T, lat: T, units: Units, formula: Formula) -> T {
+        match formula {
+            Formula::Spherical => great_circle_dist(
+                self.lng,
+                self.lat,
+                lng,
+                lat,
+                self.cos_lat,
+                self.sin_lat,
+                units.earth_radius(),
+            ),
+            Formula::Haversine => {
+                haversine_dist(self.lng, self.lat, lng, lat, units.earth_radius())
+            }
+            Formula::Ellipsoidal => distance_ellipsoidal(self.lng, self.lat, lng, lat, units),
+        }
+    }
+
+    // lower bound of `dist` to any point in the box
+    fn box_bound(&self, bbox: &BBox<T>, units: Units, formula: Formula) -> T {
+        let radius = bound_radius(units, formula);
+        // box_dist is computed with acos, which can be off by ~1e-8 radians near 0
+        let slack = radius * NumCast::from(1e-7).unwrap();
+        let dist = box_dist(self.lng, self.lat, bbox, self.cos_lat, self.sin_lat, radius);
+        T::max(dist - slack, T::zero())
+    }
+}
+
+// Radius of the sphere whose great circle distances are a lower bound of the
+// distances measured with `formula`.
+fn bound_radius<T: Float>(units: Units, formula: Formula) -> T {
+    match formula {
+        Formula::Spherical | Formula::Haversine => units.earth_radius(),
+        Formula::Ellipsoidal => min_curvature_radius(units),
+    }
+}
 
-                if max_results.is_some() && result.len() == max_results.unwrap() {
-                    println!("stop results.");
-                    return result; // this breaks the 'tree loop
+/// Ids of all points inside a geographic bounding box. Longitudes are
+/// normalised, so a box crossing the antimeridian can be given as
+/// `west > east` (e.g. 177 to -178 for Fiji) or with `east` beyond 180.
+/// Latitudes are clamped to [-90, 90].
+pub fn range_geo<T>(
+    index: &KDBush<T>,
+    west: T::CoordType,
+    south: T::CoordType,
+    east: T::CoordType,
+    north: T::CoordType,
+) -> Vec<usize>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    let mut result = vec![];
+    let (min_lng, max_lng): (T::CoordType, T::CoordType) = (
+        NumCast::from(-180.0).unwrap(),
+        NumCast::from(180.0).unwrap(),
+    );
+    let three60: T::CoordType = NumCast::from(360.0).unwrap();
+    let south = T::CoordType::max(south, NumCast::from(-90.0).unwrap());
+    let north = T::CoordType::min(north, NumCast::from(90.0).unwrap());
+    if south > north {
+        return result;
+    }
+
+    let mut width = east - west;
+    if width < num::zero() {
+        width = width + three60;
+    }
+    if width >= three60 {
+        index.range(
+            &min_lng,
+            &south,
+            &max_lng,
+            &north,
+            &mut result,
+            None,
+            None,
+            None,
+        );
+        return result;
+    }
+
+    let west = normalize_lng(west);
+    let east = west + width;
+    index.range(
+        &west,
+        &south,
+        &T::CoordType::min(east, max_lng),
+        &north,
+        &mut result,
+        None,
+        None,
+        None,
+    );
+    // -180 and 180 are the same meridian, so a box touching it from either
+    // side also has to take the points stored with the other longitude
+    if east >= max_lng {
+        // the part of the box across the antimeridian
+        index.range(
+            &min_lng,
+            &south,
+            &(east - three60),
+            &north,
+            &mut result,
+            None,
+            None,
+            None,
+        );
+    }
+    if west == min_lng {
+        index.range(
+            &max_lng,
+            &south,
+            &max_lng,
+            &north,
+            &mut result,
+            None,
+            None,
+            None,
+        );
+    }
+    result
+}
+
+/// Points within `max_dist` of `(lng, lat)` whose initial bearing from it is
+/// within `half_angle` degrees of `bearing` (clockwise from north), as
+/// `(id, item, distance)` nearest first, with distances in `units` measured
+/// with `formula`. A negative `half_angle` matches nothing. kd nodes that lie
+/// entirely outside the sector are not searched.
+#[allow(clippy::too_many_arguments)]
+pub fn around_sector<T>(
+    index: &KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    bearing: T::CoordType,
+    half_angle: T::CoordType,
+    max_dist: T::CoordType,
+    units: Units,
+    formula: Formula,
+) -> Vec<(usize, &T, T::CoordType)>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    let mut result = vec![];
+    if half_angle < num::zero() {
+        return result;
+    }
+    let sector = Sector::new(lng, lat, bearing, half_angle);
+    let mut iter = nearest_iter(index, lng, lat).units(units).formula(formula);
+    while let Some((id, item, dist)) = iter.next_with(&mut |node| sector.may_overlap(&node.bbox)) {
+        if dist > max_dist {
+            break;
+        }
+        if sector.contains(item.get_x(), item.get_y()) {
+            result.push((id, item, dist));
+        }
+    }
+    result
+}
+
+/// The `k` points with the highest `score(attribute, distance)`, best first,
+/// as `(id, item, score)`. The attribute of an item is the score function of
+/// `bounds`, built with `index.score_bounds`, and distances are in `units`
+/// measured with `formula`. `score` has to be non-decreasing in the attribute
+/// and non-increasing in the distance, e.g. `|population, d| population /
+/// (d * d)` for a gravity model: kd nodes are then searched in order of the
+/// score of their largest attribute at their smallest distance, and the
+/// search stops once no node can beat the k-th result. `bounds` have to be
+/// built from `index`.
+#[allow(clippy::too_many_arguments)]
+pub fn around_ranked<'a, T, F, S, G, R>(
+    index: &'a KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    k: usize,
+    bounds: &ScoreBounds<F, S>,
+    score: G,
+    units: Units,
+    formula: Formula,
+) -> Result<Vec<(usize, &'a T, R)>, ValidationError>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+    F: Fn(&T) -> S,
+    S: PartialOrd + Copy,
+    G: Fn(S, T::CoordType) -> R,
+    R: PartialOrd + Copy,
+{
+    bounds.check(index.ids.len(), index.node_size)?;
+    let mut result = vec![];
+    if k == 0 || index.ids.is_empty() {
+        return Ok(result);
+    }
+
+    let origin = Origin::new(lng, lat);
+    let node_entry = |node: Node<T::CoordType>| {
+        let dist = origin.box_bound(&node.bbox, units, formula);
+        let bound = bounds.node_bound(node.left, node.right, index.node_size);
+        Ranked(score(bound, dist), PointOrNode::Node(node))
+    };
+    let point_entry = |i: usize| {
+        let item = &index.points[index.ids[i]];
+        let dist = origin.dist(item.get_x(), item.get_y(), units, formula);
+        Ranked(
+            score(bounds.score(item), dist),
+            PointOrNode::Point(index.ids[i], item),
+        )
+    };
+
+    let mut q = BinaryHeap::new();
+    q.push(node_entry(Node::root(index)));
+    while let Some(Ranked(best, entry)) = q.pop() {
+        let node = match entry {
+            PointOrNode::Point(id, item) => {
+                result.push((id, item, best));
+                if result.len() == k {
+                    break;
                 }
-            } else {
-                // no point found, this is a branch node
-                break;
-            };
+                continue;
+            }
+            PointOrNode::Node(node) => node,
+        };
+
+        if node.right - node.left <= index.node_size {
+            q.extend((node.left..node.right + 1).map(point_entry));
+            continue;
         }
+        let (m, [left, right]) = node.split(index);
+        q.push(point_entry(m));
+        q.push(node_entry(left));
+        q.push(node_entry(right));
+    }
+    Ok(result)
+}
+
+// an entry on the `around_ranked` queue, highest score first
+struct Ranked<R, E>(R, E);
 
-        println!("heap length : \t{:?}", q.len());
-        let node_dp = q.pop();
+impl<R: PartialOrd, E> PartialEq for Ranked<R, E> {
+    fn eq(&self, other: &Ranked<R, E>) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<R: PartialOrd, E> Eq for Ranked<R, E> {}
+
+impl<R: PartialOrd, E> Ord for Ranked<R, E> {
+    fn cmp(&self, other: &Ranked<R, E>) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
 
-        match node_dp.unwrap() {
-            PointDist(p, _) => {
-                point_or_node = p;
+impl<R: PartialOrd, E> PartialOrd for Ranked<R, E> {
+    fn partial_cmp(&self, other: &Ranked<R, E>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The bearings `bearing +- half_angle` seen from a point, as the half spaces
+// on the clockwise side of `edges[0]` and the anticlockwise side of
+// `edges[1]`. Both great circles run through the point and its antipode, so
+// a window of at most 180 degrees is the intersection of the two half
+// spaces and a wider one their union.
+struct Sector<T> {
+    normals: [[T; 3]; 2],
+    wide: bool,
+    all: bool,
+}
+
+impl<T: Float + FloatConst> Sector<T> {
+    fn new(lng: T, lat: T, bearing: T, half_angle: T) -> Sector<T> {
+        let (sin_lng, cos_lng) = (lng * rad::<T>()).sin_cos();
+        let (sin_lat, cos_lat) = (lat * rad::<T>()).sin_cos();
+        // north and east at the point
+        let north = [-sin_lat * cos_lng, -sin_lat * sin_lng, cos_lat];
+        let east = [-sin_lng, cos_lng, T::zero()];
+        let direction = |angle: T| {
+            let (sin, cos) = (angle * rad::<T>()).sin_cos();
+            [
+                cos * north[0] + sin * east[0],
+                cos * north[1] + sin * east[1],
+                cos * north[2] + sin * east[2],
+            ]
+        };
+        let ninety: T = NumCast::from(90.0).unwrap();
+        Sector {
+            normals: [
+                direction(bearing - half_angle + ninety),
+                direction(bearing + half_angle - ninety),
+            ],
+            wide: half_angle > ninety,
+            all: half_angle >= ninety + ninety,
+        }
+    }
+
+    fn contains(&self, lng: T, lat: T) -> bool {
+        let p = unit_vector(lng, lat);
+        let inside = |n: &[T; 3]| dot(n, &p) >= T::zero();
+        self.combine(inside(&self.normals[0]), inside(&self.normals[1]))
+    }
+
+    // false only if no point of the box is in the sector
+    fn may_overlap(&self, bbox: &BBox<T>) -> bool {
+        // box_max_dot is exact up to rounding, which the slack covers
+        let slack: T = NumCast::from(1e-9).unwrap();
+        let overlaps = |n: &[T; 3]| box_max_dot(n, bbox) >= -slack;
+        self.combine(overlaps(&self.normals[0]), overlaps(&self.normals[1]))
+    }
+
+    fn combine(&self, a: bool, b: bool) -> bool {
+        self.all || if self.wide { a || b } else { a && b }
+    }
+}
+
+// Largest dot product of `n` with the unit vector of a point in the box.
+fn box_max_dot<T: Float + FloatConst>(n: &[T; 3], bbox: &BBox<T>) -> T {
+    // n . p = cos(lat) * a * cos(lng - lng0) + n[2] * sin(lat); cos(lat) is
+    // never negative, so first maximise cos(lng - lng0) over the longitudes
+    let a = n[0].hypot(n[1]);
+    let lng0 = n[1].atan2(n[0]);
+    let (min_lng, max_lng) = (bbox.min_x * rad::<T>(), bbox.max_x * rad::<T>());
+    let two_pi = T::PI() + T::PI();
+    let mut offset = (lng0 - min_lng) % two_pi;
+    if offset < T::zero() {
+        offset = offset + two_pi;
+    }
+    let c = if min_lng + offset <= max_lng {
+        T::one()
+    } else {
+        T::max((min_lng - lng0).cos(), (max_lng - lng0).cos())
+    };
+
+    // then a sinusoid in the latitude, which peaks at lat0
+    let (min_lat, max_lat) = (bbox.min_y * rad::<T>(), bbox.max_y * rad::<T>());
+    let f = |lat: T| a * c * lat.cos() + n[2] * lat.sin();
+    let lat0 = n[2].atan2(a * c);
+    if lat0 >= min_lat && lat0 <= max_lat {
+        f(lat0)
+    } else {
+        T::max(f(min_lat), f(max_lat))
+    }
+}
+
+/// Ids of all points within `radius` of `(lng, lat)`, in no particular order,
+/// with distances in `units` measured with `formula`.
+pub fn within_radius<T>(
+    index: &KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    radius: T::CoordType,
+    units: Units,
+    formula: Formula,
+) -> Vec<usize>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    let mut result = vec![];
+    let origin = Origin::new(lng, lat);
+    // no point in the cap of this angle is within the radius
+    let cap = cap_bbox(lng, lat, radius / bound_radius(units, formula));
+    walk(
+        index,
+        |node| {
+            cap.iter().any(|b| intersects(b, &node.bbox))
+                && origin.box_bound(&node.bbox, units, formula) <= radius
+        },
+        |i| {
+            let p = &index.points[index.ids[i]];
+            if origin.dist(p.get_x(), p.get_y(), units, formula) <= radius {
+                result.push(index.ids[i]);
             }
+        },
+    );
+    result
+}
+
+/// `within_radius` in great circle km.
+pub fn within_km<T>(
+    index: &KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    radius: T::CoordType,
+) -> Vec<usize>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    within_radius(
+        index,
+        lng,
+        lat,
+        radius,
+        Units::Kilometers,
+        Formula::Spherical,
+    )
+}
+
+/// Ids of all points inside a polygon on the sphere, given as rings of
+/// `(lng, lat)` vertices with the outer ring first and holes after it. Edges
+/// are great circle arcs, so rings crossing the antimeridian need no special
+/// treatment, but the polygon must not contain a pole. The bounding box of
+/// the outer ring is searched with `range_geo` and the points in it are
+/// tested with the even-odd rule.
+pub fn within_polygon<T>(
+    index: &KDBush<T>,
+    polygon: &[Vec<(T::CoordType, T::CoordType)>],
+) -> Vec<usize>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    let outer = match polygon.first() {
+        Some(ring) if ring.len() > 2 => ring,
+        _ => return vec![],
+    };
+    let bbox = ring_bbox(outer);
+    let mut result = range_geo(index, bbox.min_x, bbox.min_y, bbox.max_x, bbox.max_y);
+    result.retain(|&id| {
+        let p = &index.points[id];
+        sphere_polygon_contains(polygon, p.get_x(), p.get_y())
+    });
+    result
+}
+
+/// A point found by `along_route`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouteMatch<T> {
+    pub id: usize,
+    /// Distance to the closest point of the route.
+    pub distance: T,
+    /// Distance along the route from its start to that closest point.
+    pub along: T,
+}
+
+/// All points within `max_dist` of a polyline of `(lng, lat)` vertices with
+/// great circle segments, ordered by their position along the route.
+/// Distances are in `units`; with `Formula::Ellipsoidal` they are geodesics
+/// to the closest point of the great circle segment. The kd tree is searched
+/// once for the whole route: a node is skipped when it is further than
+/// `max_dist` from the caps around every segment.
+pub fn along_route<T>(
+    index: &KDBush<T>,
+    route: &[(T::CoordType, T::CoordType)],
+    max_dist: T::CoordType,
+    units: Units,
+    formula: Formula,
+) -> Vec<RouteMatch<T::CoordType>>
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    let mut result = vec![];
+    if route.is_empty() {
+        return result;
+    }
+
+    // a route of a single point is a segment of length zero
+    let pairs: Vec<_> = match route.len() {
+        1 => vec![(route[0], route[0])],
+        _ => route.windows(2).map(|w| (w[0], w[1])).collect(),
+    };
+    let mut start = num::zero();
+    let mut segments = vec![];
+    for (a, b) in pairs {
+        let segment = RouteSegment::new(a, b, start, units, formula);
+        start = start + segment.length;
+        segments.push(segment);
+    }
+    let radius: T::CoordType = bound_radius(units, formula);
+
+    walk(
+        index,
+        |node| {
+            segments.iter().any(|s| {
+                s.mid.box_bound(&node.bbox, units, formula)
+                    <= s.angle * radius / NumCast::from(2.0).unwrap() + max_dist
+            })
+        },
+        |i| {
+            let p = &index.points[index.ids[i]];
+            let closest = segments
+                .iter()
+                .map(|s| s.closest(p.get_x(), p.get_y(), units, formula))
+                .fold(
+                    None,
+                    |best: Option<(T::CoordType, T::CoordType)>, c| match best {
+                        Some(best) if best.0 <= c.0 => Some(best),
+                        _ => Some(c),
+                    },
+                );
+            if let Some((distance, along)) = closest.filter(|c| c.0 <= max_dist) {
+                result.push(RouteMatch {
+                    id: index.ids[i],
+                    distance,
+                    along,
+                });
+            }
+        },
+    );
+
+    result.sort_by(|a, b| {
+        (a.along, a.distance)
+            .partial_cmp(&(b.along, b.distance))
+            .unwrap_or(Ordering::Equal)
+    });
+    result
+}
+
+// A great circle segment of a route, with the cap around its midpoint that
+// covers it.
+struct RouteSegment<T> {
+    a: [T; 3],
+    b: [T; 3],
+    from: (T, T),
+    // unit normal of the plane of the segment, zero if it is a single point
+    normal: [T; 3],
+    // distance along the route to `a`, and the length of the segment
+    start: T,
+    length: T,
+    mid: Origin<T>,
+    // length of the segment in radians, twice the radius of the cap
+    angle: T,
+}
+
+impl<T: Float + FloatConst> RouteSegment<T> {
+    fn new(a: (T, T), b: (T, T), start: T, units: Units, formula: Formula) -> RouteSegment<T> {
+        let (va, vb) = (unit_vector(a.0, a.1), unit_vector(b.0, b.1));
+        let normal = unit_normal(&va, &vb);
+        let angle = angle_between(&va, &vb);
+        let length = match formula {
+            Formula::Ellipsoidal => distance_ellipsoidal(a.0, a.1, b.0, b.1, units),
+            _ => angle * units.earth_radius(),
+        };
+        let (mid_lng, mid_lat) = lng_lat(&[va[0] + vb[0], va[1] + vb[1], va[2] + vb[2]]);
+        RouteSegment {
+            a: va,
+            b: vb,
+            from: a,
+            normal,
+            start,
+            length,
+            mid: Origin::new(mid_lng, mid_lat),
+            angle,
+        }
+    }
+
+    // distance to the closest point of the segment and the position of that
+    // point along the route
+    fn closest(&self, lng: T, lat: T, units: Units, formula: Formula) -> (T, T) {
+        let p = unit_vector(lng, lat);
+        let (cross_track, along, proj) = track(&p, &self.a, &self.normal);
+        let (closest, angle) = if along > T::zero() && along < self.angle {
+            (proj, cross_track.abs())
+        } else if angle_between(&self.a, &p) <= angle_between(&self.b, &p) {
+            (self.a, angle_between(&self.a, &p))
+        } else {
+            (self.b, angle_between(&self.b, &p))
         };
+
+        if formula == Formula::Ellipsoidal {
+            let (c_lng, c_lat) = lng_lat(&closest);
+            let (a_lng, a_lat) = self.from;
+            return (
+                distance_ellipsoidal(lng, lat, c_lng, c_lat, units),
+                self.start + distance_ellipsoidal(a_lng, a_lat, c_lng, c_lat, units),
+            );
+        }
+        let earth_radius: T = units.earth_radius();
+        (
+            angle * earth_radius,
+            self.start + angle_between(&self.a, &closest) * earth_radius,
+        )
+    }
+}
+
+// Bounding box of a ring with great circle edges. Longitudes are unwrapped
+// along the ring, so `max_x` is beyond 180 if it crosses the antimeridian.
+fn ring_bbox<T: Float + FloatConst>(ring: &[(T, T)]) -> BBox<T> {
+    let (mut lng, lat) = ring[0];
+    let mut bbox = BBox {
+        min_x: lng,
+        min_y: lat,
+        max_x: lng,
+        max_y: lat,
+    };
+    for (i, &(lng1, lat1)) in ring.iter().enumerate() {
+        let (lng2, lat2) = ring[(i + 1) % ring.len()];
+        lng = lng + normalize_lng(lng2 - lng1);
+        bbox.min_x = T::min(bbox.min_x, lng);
+        bbox.max_x = T::max(bbox.max_x, lng);
+
+        // the arc bulges towards a pole past the latitude of its ends
+        let (min_lat, max_lat) = arc_lat_extent(lng1, lat1, lng2, lat2);
+        bbox.min_y = T::min(bbox.min_y, min_lat);
+        bbox.max_y = T::max(bbox.max_y, max_lat);
+    }
+    bbox
+}
+
+// Smallest and largest latitude on the great circle arc between two points.
+fn arc_lat_extent<T: Float + FloatConst>(lng1: T, lat1: T, lng2: T, lat2: T) -> (T, T) {
+    let a = unit_vector(lng1, lat1);
+    let b = unit_vector(lng2, lat2);
+    let n = cross(&a, &b);
+    let (mut min_lat, mut max_lat) = (T::min(lat1, lat2), T::max(lat1, lat2));
+    // the northernmost point of the great circle, the north pole projected
+    // onto the plane of the arc
+    let top = [-n[0] * n[2], -n[1] * n[2], n[0] * n[0] + n[1] * n[1]];
+    let on_arc =
+        |m: &[T; 3]| dot(&cross(&a, m), &n) > T::zero() && dot(&cross(m, &b), &n) > T::zero();
+    let lat_of = |m: &[T; 3]| m[2].atan2(m[0].hypot(m[1])) / rad::<T>();
+    if on_arc(&top) {
+        max_lat = T::max(max_lat, lat_of(&top));
+    }
+    let bottom = [-top[0], -top[1], -top[2]];
+    if on_arc(&bottom) {
+        min_lat = T::min(min_lat, lat_of(&bottom));
+    }
+    (min_lat, max_lat)
+}
+
+// Even-odd test of a point against all rings of a polygon with great circle
+// edges, counting the edges crossed by the meridian going north from it.
+fn sphere_polygon_contains<T: Float + FloatConst>(polygon: &[Vec<(T, T)>], lng: T, lat: T) -> bool {
+    let one80: T = NumCast::from(180.0).unwrap();
+    let tan_lat = (lat * rad::<T>()).tan();
+    let mut inside = false;
+    for ring in polygon {
+        for (i, &(lng1, lat1)) in ring.iter().enumerate() {
+            let (lng2, lat2) = ring[(i + 1) % ring.len()];
+            let t1 = normalize_lng(lng1 - lng) * rad::<T>();
+            let t2 = normalize_lng(lng2 - lng) * rad::<T>();
+            // the edge has to cross the meridian of the point, not the one
+            // on the other side of the Earth
+            if (t1 >= T::zero()) == (t2 >= T::zero()) || (t2 - t1).abs() >= one80 * rad::<T>() {
+                continue;
+            }
+            // latitude of the arc at the longitude of the point
+            let tan1 = (lat1 * rad::<T>()).tan();
+            let tan2 = (lat2 * rad::<T>()).tan();
+            let tan_cross = (tan1 * t2.sin() - tan2 * t1.sin()) / (t2 - t1).sin();
+            if tan_cross > tan_lat {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+// Bounding boxes of the spherical cap of `angle` radians around a point, split
+// in two where it crosses the antimeridian.
+fn cap_bbox<T: Float + FloatConst>(lng: T, lat: T, angle: T) -> Vec<BBox<T>> {
+    let one80: T = NumCast::from(180.0).unwrap();
+    let three60: T = NumCast::from(360.0).unwrap();
+    let ninety: T = NumCast::from(90.0).unwrap();
+    let delta = angle / rad::<T>();
+
+    let min_lat = lat - delta;
+    let max_lat = lat + delta;
+    if min_lat <= -ninety || max_lat >= ninety || delta >= one80 {
+        // the cap contains a pole, so it spans all longitudes
+        return vec![BBox {
+            min_x: -one80,
+            min_y: T::max(min_lat, -ninety),
+            max_x: one80,
+            max_y: T::min(max_lat, ninety),
+        }];
+    }
+
+    let ratio = T::sin(delta * rad::<T>()) / T::cos(lat * rad::<T>());
+    let delta_lng = T::asin(T::min(ratio, T::one())) / rad::<T>();
+    let west = normalize_lng(lng - delta_lng);
+    let east = west + delta_lng + delta_lng;
+    if east <= one80 {
+        return vec![BBox {
+            min_x: west,
+            min_y: min_lat,
+            max_x: east,
+            max_y: max_lat,
+        }];
     }
+    vec![
+        BBox {
+            min_x: west,
+            min_y: min_lat,
+            max_x: one80,
+            max_y: max_lat,
+        },
+        BBox {
+            min_x: -one80,
+            min_y: min_lat,
+            max_x: east - three60,
+            max_y: max_lat,
+        },
+    ]
 }
 
-fn box_dist<T>(lng: T, lat: T, node: Box<&Node<T>>, cos_lat: T, sin_lat: T) -> T
+fn intersects<T: Float>(a: &BBox<T>, b: &BBox<T>) -> bool {
+    a.min_x <= b.max_x && a.max_x >= b.min_x && a.min_y <= b.max_y && a.max_y >= b.min_y
+}
+
+fn box_dist<T>(lng: T, lat: T, bbox: &BBox<T>, cos_lat: T, sin_lat: T, radius: T) -> T
 where
     T: Float + FloatConst + PartialOrd,
 {
     let three60 = NumCast::from(360.0).unwrap();
-    if lng >= node.min_lng && lng <= node.max_lng {
+    if lng >= bbox.min_x && lng <= bbox.max_x {
         let lat = match lat {
-            lat if lat <= node.min_lat => {
-                earth_circumference::<T>() * (node.min_lat - lat) / three60
-            }
-            lat if lat >= node.max_lat => {
-                earth_circumference::<T>() * (lat - node.max_lat) / three60
-            }
+            lat if lat <= bbox.min_y => radius * (bbox.min_y - lat) * rad::<T>(),
+            lat if lat >= bbox.max_y => radius * (lat - bbox.max_y) * rad::<T>(),
             _ => NumCast::from(0.0).unwrap(),
         };
         return lat;
     }
 
     let closest_lng =
-        if (node.min_lng - lng + three60) % three60 <= (lng - node.max_lng + three60) % three60 {
-            node.min_lng
+        if (bbox.min_x - lng + three60) % three60 <= (lng - bbox.max_x + three60) % three60 {
+            bbox.min_x
         } else {
-            node.max_lng
+            bbox.max_x
         };
     let cos_lng_delta = T::cos((closest_lng - lng) * rad::<T>());
     let extremum_lat = T::atan(sin_lat / (cos_lat * cos_lng_delta)) / rad::<T>();
 
     let mut d = T::max(
-        great_circle_dist_part(node.min_lat, cos_lat, sin_lat, cos_lng_delta),
-        great_circle_dist_part(node.max_lat, cos_lat, sin_lat, cos_lng_delta),
+        great_circle_dist_part(bbox.min_y, cos_lat, sin_lat, cos_lng_delta),
+        great_circle_dist_part(bbox.max_y, cos_lat, sin_lat, cos_lng_delta),
     );
 
-    if extremum_lat > node.min_lat && extremum_lat < node.max_lat {
+    if extremum_lat > bbox.min_y && extremum_lat < bbox.max_y {
         d = T::max(
             d,
             great_circle_dist_part(extremum_lat, cos_lat, sin_lat, cos_lng_delta),
         )
     }
 
-    earth_radius::<T>() * T::acos(d)
+    radius * T::acos(d)
 }
 
-fn great_circle_dist<T>(lng: T, _: T, lng2: T, lat2: T, cos_lat: T, sin_lat: T) -> T
+fn great_circle_dist<T>(lng: T, _: T, lng2: T, lat2: T, cos_lat: T, sin_lat: T, radius: T) -> T
 where
     T: Float + PartialOrd + FloatConst + std::ops::Mul<Output = T>,
 {
     let cos_lng_delta = T::cos((lng2 - lng) * rad::<T>());
-    earth_radius::<T>() * T::acos(great_circle_dist_part(
-        lat2,
-        cos_lat,
-        sin_lat,
-        cos_lng_delta,
-    ))
+    radius
+        * T::acos(great_circle_dist_part(
+            lat2,
+            cos_lat,
+            sin_lat,
+            cos_lng_delta,
+        ))
 }
 
 fn great_circle_dist_part<T>(lat: T, cos_lat: T, sin_lat: T, cos_lng_delta: T) -> T
@@ -345,16 +1432,226 @@ where
     T::min(d, num::one::<T>())
 }
 
-pub fn distance<T>(lng: T, lat: T, lng2: T, lat2: T) -> T
+pub fn distance<T>(lng: T, lat: T, lng2: T, lat2: T, units: Units, formula: Formula) -> T
 where
     T: Float + PartialOrd + FloatConst,
 {
-    great_circle_dist(
+    match formula {
+        Formula::Spherical => great_circle_dist(
+            lng,
+            lat,
+            lng2,
+            lat2,
+            T::cos(lat * rad::<T>()),
+            T::sin(lat * rad::<T>()),
+            units.earth_radius(),
+        ),
+        Formula::Haversine => haversine_dist(lng, lat, lng2, lat2, units.earth_radius()),
+        Formula::Ellipsoidal => distance_ellipsoidal(lng, lat, lng2, lat2, units),
+    }
+}
+
+fn haversine_dist<T>(lng: T, lat: T, lng2: T, lat2: T, radius: T) -> T
+where
+    T: Float + FloatConst,
+{
+    let two = T::one() + T::one();
+    let sin_half_lat = T::sin((lat2 - lat) * rad::<T>() / two);
+    let sin_half_lng = T::sin((lng2 - lng) * rad::<T>() / two);
+    let a = sin_half_lat * sin_half_lat
+        + T::cos(lat * rad::<T>()) * T::cos(lat2 * rad::<T>()) * sin_half_lng * sin_half_lng;
+    let a = T::min(a, T::one());
+    radius * two * T::atan2(a.sqrt(), (T::one() - a).sqrt())
+}
+
+/// Geodesic distance on the WGS84 ellipsoid using Vincenty's inverse formula,
+/// accurate to well below a millimetre. For the rare nearly antipodal pairs
+/// where the iteration does not converge, the haversine distance is returned.
+pub fn distance_ellipsoidal<T>(lng: T, lat: T, lng2: T, lat2: T, units: Units) -> T
+where
+    T: Float + FloatConst,
+{
+    let to_rad = |v: T| v.to_f64().unwrap().to_radians();
+    let (a, f) = (WGS84_A_KM, WGS84_F);
+    let b = (1.0 - f) * a;
+
+    let l = to_rad(lng2) - to_rad(lng);
+    let u1 = ((1.0 - f) * to_rad(lat).tan()).atan();
+    let u2 = ((1.0 - f) * to_rad(lat2).tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+
+    let mut lambda = l;
+    for _ in 0..200 {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2))
+        .sqrt();
+        if sin_sigma == 0.0 {
+            // coincident points
+            return T::zero();
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_sq_alpha = 1.0 - sin_alpha * sin_alpha;
+        let cos_2sigma_m = if cos_sq_alpha != 0.0 {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos_sq_alpha
+        } else {
+            // both points on the equator
+            0.0
+        };
+        let c = f / 16.0 * cos_sq_alpha * (4.0 + f * (4.0 - 3.0 * cos_sq_alpha));
+        let lambda_prev = lambda;
+        lambda = l
+            + (1.0 - c)
+                * f
+                * sin_alpha
+                * (sigma
+                    + c * sin_sigma
+                        * (cos_2sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))));
+
+        if (lambda - lambda_prev).abs() < 1e-12 {
+            let u_sq = cos_sq_alpha * (a * a - b * b) / (b * b);
+            let big_a =
+                1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+            let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+            let delta_sigma = big_b
+                * sin_sigma
+                * (cos_2sigma_m
+                    + big_b / 4.0
+                        * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))
+                            - big_b / 6.0
+                                * cos_2sigma_m
+                                * (-3.0 + 4.0 * sin_sigma.powi(2))
+                                * (-3.0 + 4.0 * cos_2sigma_m.powi(2))));
+            let s = b * big_a * (sigma - delta_sigma);
+            return NumCast::from(s * units.per_km()).unwrap();
+        }
+    }
+    distance(lng, lat, lng2, lat2, units, Formula::Haversine)
+}
+
+// The smallest radius of curvature of the WGS84 ellipsoid, the meridional one
+// at the equator. The surface normal turns by at most 1 / radius per unit of
+// length, so a geodesic between two points is never shorter than this radius
+// times the angle between their normals, which is the spherical central angle
+// of their geodetic coordinates.
+fn min_curvature_radius<T: Float>(units: Units) -> T {
+    NumCast::from(WGS84_A_KM * (1.0 - WGS84_F).powi(2) * units.per_km()).unwrap()
+}
+
+/// Exports the bounding boxes of all kd nodes, as `around` computes them, as a
+/// GeoJSON FeatureCollection of polygons.
+pub fn nodes_to_geojson<T>(index: &KDBush<T>) -> Value
+where
+    T: Coords,
+    T::CoordType: Float + FloatConst,
+{
+    let features: Vec<Value> = index
+        .nodes_in(world_bbox())
+        .map(|node| {
+            bbox_feature(
+                &node.bbox,
+                json!({
+                    "left": node.left,
+                    "right": node.right,
+                    "depth": node.depth,
+                    "axis": node.axis,
+                    "leaf": node.leaf,
+                }),
+            )
+        })
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+/// Runs `around` and exports the kd nodes it visited as GeoJSON polygons with
+/// their visiting `order`, followed by the query point and the results with
+/// their id and distance.
+#[allow(clippy::too_many_arguments)]
+pub fn around_to_geojson<T>(
+    index: &KDBush<T>,
+    lng: T::CoordType,
+    lat: T::CoordType,
+    max_results: Option<usize>,
+    max_distance: Option<T::CoordType>,
+    predicate: &Predicate<T>,
+    units: Units,
+    formula: Formula,
+) -> Value
+where
+    T: Coords,
+    T::CoordType: Float + PartialOrd + FloatConst,
+{
+    let mut features = vec![];
+    let (result, _) = traverse(
+        index,
         lng,
         lat,
-        lng2,
-        lat2,
-        T::cos(lat * rad::<T>()),
-        T::sin(lat * rad::<T>()),
-    )
+        max_results,
+        max_distance,
+        predicate,
+        units,
+        formula,
+        |node| {
+            let order = features.len();
+            features.push(bbox_feature(
+                &node.bbox,
+                json!({
+                    "order": order,
+                    "left": node.left,
+                    "right": node.right,
+                    "axis": node.axis,
+                    "leaf": node.right - node.left <= index.node_size,
+                }),
+            ));
+        },
+    );
+
+    features.push(point_feature(lng, lat, json!({ "query": true })));
+    for (rank, &(id, p, dist)) in result.iter().enumerate() {
+        features.push(point_feature(
+            p.get_x(),
+            p.get_y(),
+            json!({ "rank": rank, "id": id, "distance": dist.to_f64() }),
+        ));
+    }
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+fn world_bbox<T: Float>() -> BBox<T> {
+    BBox {
+        min_x: NumCast::from(-180.0).unwrap(),
+        min_y: NumCast::from(-90.0).unwrap(),
+        max_x: NumCast::from(180.0).unwrap(),
+        max_y: NumCast::from(90.0).unwrap(),
+    }
+}
+
+fn bbox_feature<T: Float>(bbox: &BBox<T>, properties: Value) -> Value {
+    let (min_x, min_y) = (bbox.min_x.to_f64(), bbox.min_y.to_f64());
+    let (max_x, max_y) = (bbox.max_x.to_f64(), bbox.max_y.to_f64());
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Polygon",
+            "coordinates": [[
+                [min_x, min_y],
+                [max_x, min_y],
+                [max_x, max_y],
+                [min_x, max_y],
+                [min_x, min_y],
+            ]],
+        },
+        "properties": properties,
+    })
+}
+
+pub(crate) fn point_feature<T: Float>(lng: T, lat: T, properties: Value) -> Value {
+    json!({
+        "type": "Feature",
+        "geometry": { "type": "Point", "coordinates": [lng.to_f64(), lat.to_f64()] },
+        "properties": properties,
+    })
 }
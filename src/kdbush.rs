@@ -5,10 +5,14 @@ extern crate serde_derive;
 extern crate serde_json;
 
 use conv::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 use std::fmt;
 
-use num::Num;
-use serde_derive::Deserialize;
+use num::{Num, Signed};
+use serde_derive::{Deserialize, Serialize};
+
+pub use kdbush_derive::Coords;
 
 pub struct KDBush<T>
 where
@@ -18,6 +22,9 @@ where
     pub points: Vec<T>,
     pub node_size: usize,
     pub ids: Vec<usize>,
+    // size of the domain `[0, px) x [0, py)` if it wraps around at the
+    // edges, see `with_period`
+    period: Option<(T::CoordType, T::CoordType)>,
 }
 
 type TIndex = usize;
@@ -79,17 +86,20 @@ where
     }
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+/// A place from the all-the-cities dataset, the GeoNames places with at
+/// least 1000 inhabitants. The codes are those of GeoNames.
+#[derive(Debug, PartialEq, Deserialize, Coords)]
 #[serde(rename_all = "camelCase")]
+#[coords(x = "lon", y = "lat", crate = "crate")]
 pub struct City {
     pub name: String,
-    country: String,
-    alt_country: String,
-    muni: String,
-    muni_sub: String,
-    feature_class: String,
-    feature_code: String,
-    admin_code: String,
+    pub country: String,
+    pub alt_country: String,
+    pub muni: String,
+    pub muni_sub: String,
+    pub feature_class: String,
+    pub feature_code: String,
+    pub admin_code: String,
     pub population: u32,
     pub lat: f64,
     pub lon: f64,
@@ -107,29 +117,261 @@ where
     }
 }
 
-pub trait Coords {
-    type CoordType: Num + PartialOrd;
-    fn get_x(&self) -> Self::CoordType;
-    fn get_y(&self) -> Self::CoordType;
-    fn get(&self, i: i8) -> Self::CoordType;
+/// Axis aligned bounding box of a kd node.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BBox<T> {
+    pub min_x: T,
+    pub min_y: T,
+    pub max_x: T,
+    pub max_y: T,
 }
 
-impl Coords for City {
-    type CoordType = f64;
-    fn get_x(&self) -> <City as Coords>::CoordType {
-        self.lon
+impl<T> BBox<T>
+where
+    T: Copy,
+{
+    /// Splits the box of a node on `axis` at its median point `(x, y)` into
+    /// the boxes of its left and right child.
+    pub fn split(&self, axis: u8, x: T, y: T) -> (BBox<T>, BBox<T>) {
+        let mut left = *self;
+        let mut right = *self;
+        if axis == 0 {
+            left.max_x = x;
+            right.min_x = x;
+        } else {
+            left.max_y = y;
+            right.min_y = y;
+        }
+        (left, right)
     }
-    fn get_y(&self) -> <City as Coords>::CoordType {
-        self.lat
+}
+
+/// An implicit node of the kd tree, as yielded by `KDBush::nodes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KDNode<T> {
+    /// Range `left..=right` of `ids` covered by the node.
+    pub left: usize,
+    pub right: usize,
+    pub depth: usize,
+    pub axis: u8,
+    /// Coordinate of the median point on `axis`, `None` for leaves.
+    pub split: Option<T>,
+    pub bbox: BBox<T>,
+    pub leaf: bool,
+}
+
+// left, right, depth, axis and bounding box of a node still to be visited
+type PendingNode<T> = (usize, usize, usize, u8, BBox<T>);
+
+/// Iterator over the nodes of a `KDBush`, depth first.
+pub struct Nodes<'a, T>
+where
+    T: Coords,
+    T::CoordType: Num + PartialOrd,
+{
+    index: &'a KDBush<T>,
+    stack: Vec<PendingNode<T::CoordType>>,
+}
+
+impl<'a, T> Iterator for Nodes<'a, T>
+where
+    T: Coords,
+    T::CoordType: Num + PartialOrd + Copy,
+{
+    type Item = KDNode<T::CoordType>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (left, right, depth, axis, bbox) = self.stack.pop()?;
+        if right - left <= self.index.node_size {
+            return Some(KDNode {
+                left,
+                right,
+                depth,
+                axis,
+                split: None,
+                bbox,
+                leaf: true,
+            });
+        }
+
+        let m = (left + right) >> 1;
+        let p = &self.index.points[self.index.ids[m]];
+        let (left_bbox, right_bbox) = bbox.split(axis, p.get_x(), p.get_y());
+        let next_axis = (axis + 1) % 2;
+        self.stack
+            .push((m + 1, right, depth + 1, next_axis, right_bbox));
+        self.stack
+            .push((left, m - 1, depth + 1, next_axis, left_bbox));
+
+        Some(KDNode {
+            left,
+            right,
+            depth,
+            axis,
+            split: Some(p.get(axis as i8)),
+            bbox,
+            leaf: false,
+        })
     }
-    fn get(&self, i: i8) -> <City as Coords>::CoordType {
-        match i {
-            0 => self.lon,
-            _ => self.lat,
+}
+
+/// Shape of a `KDBush` tree, see `KDBush::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    /// Depth of the deepest node, the root being at depth 0.
+    pub depth: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    /// `leaf_fill[n]` is the number of leaves holding `n` points.
+    pub leaf_fill: Vec<usize>,
+    /// Bytes used by the index, including spare capacity of its vectors.
+    pub memory: usize,
+}
+
+/// Upper bounds of a score function over every kd node of a `KDBush`, built
+/// with `KDBush::score_bounds` and used by `range_top_k` and `within_top_k` to
+/// skip subtrees that cannot beat the current k-th best item.
+pub struct ScoreBounds<F, S> {
+    score: F,
+    // max score of the subtree at the median of each branch and at the first
+    // position of each leaf
+    bounds: Vec<S>,
+    // node size of the index the bounds were built for
+    node_size: usize,
+}
+
+impl<F, S> ScoreBounds<F, S>
+where
+    S: Copy,
+{
+    pub(crate) fn score<T>(&self, item: &T) -> S
+    where
+        F: Fn(&T) -> S,
+    {
+        (self.score)(item)
+    }
+
+    // the bounds only fit an index of the same size and node size
+    pub(crate) fn check(&self, ids: usize, node_size: usize) -> Result<(), ValidationError> {
+        if self.bounds.len() != ids || self.node_size != node_size {
+            return Err(ValidationError::ScoreBoundsMismatch {
+                bounds: self.bounds.len(),
+                ids,
+            });
+        }
+        Ok(())
+    }
+
+    // the bound of the node spanning `left..=right` of the tree's ids
+    pub(crate) fn node_bound(&self, left: TIndex, right: TIndex, node_size: usize) -> S {
+        self.bounds[if right - left <= node_size {
+            left
+        } else {
+            (left + right) >> 1
+        }]
+    }
+}
+
+// an entry on a heap, lowest score first
+struct Scored<S, I = TIndex>(S, I);
+
+impl<S: PartialOrd, I> PartialEq for Scored<S, I> {
+    fn eq(&self, other: &Scored<S, I>) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<S: PartialOrd, I> Eq for Scored<S, I> {}
+
+impl<S: PartialOrd, I> Ord for Scored<S, I> {
+    fn cmp(&self, other: &Scored<S, I>) -> Ordering {
+        other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl<S: PartialOrd, I> PartialOrd for Scored<S, I> {
+    fn partial_cmp(&self, other: &Scored<S, I>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// a point or a node still to be expanded on the `nearest` heap
+enum Nearest<T> {
+    Point(TIndex),
+    Node(TIndex, TIndex, u8, BBox<T>),
+}
+
+/// Why `KDBush::validate` or a top-k query rejected an index.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// `ids` and `points` have a different length.
+    LengthMismatch { points: usize, ids: usize },
+    /// `node_size` is 0, which would never produce a leaf.
+    InvalidNodeSize(usize),
+    /// `ids[position]` is out of bounds or a duplicate of an earlier id.
+    NotAPermutation { position: usize, id: usize },
+    /// The item at `index` lies on the wrong side of the median of the node
+    /// spanning `left..=right`, split on `axis`.
+    SplitOrder {
+        left: usize,
+        right: usize,
+        axis: u8,
+        index: usize,
+    },
+    /// The period of one of the axes is not positive.
+    InvalidPeriod,
+    /// The point `id` lies outside the periodic domain.
+    OutsidePeriod { id: usize },
+    /// The `ScoreBounds` of a top-k query were built for another index.
+    ScoreBoundsMismatch { bounds: usize, ids: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::LengthMismatch { points, ids } => {
+                write!(formatter, "index has {} points but {} ids", points, ids)
+            }
+            ValidationError::InvalidNodeSize(node_size) => {
+                write!(formatter, "invalid node size {}", node_size)
+            }
+            ValidationError::NotAPermutation { position, id } => write!(
+                formatter,
+                "ids is not a permutation: id {} at position {}",
+                id, position
+            ),
+            ValidationError::SplitOrder {
+                left,
+                right,
+                axis,
+                index,
+            } => write!(
+                formatter,
+                "item at {} is on the wrong side of the median of node ({},{}) on axis {}",
+                index, left, right, axis
+            ),
+            ValidationError::InvalidPeriod => write!(formatter, "period has to be positive"),
+            ValidationError::OutsidePeriod { id } => {
+                write!(formatter, "point {} lies outside the periodic domain", id)
+            }
+            ValidationError::ScoreBoundsMismatch { bounds, ids } => write!(
+                formatter,
+                "score bounds of {} items do not fit the index of {} items",
+                bounds, ids
+            ),
         }
     }
 }
 
+impl std::error::Error for ValidationError {}
+
+pub trait Coords {
+    type CoordType: Num + PartialOrd;
+    fn get_x(&self) -> Self::CoordType;
+    fn get_y(&self) -> Self::CoordType;
+    fn get(&self, i: i8) -> Self::CoordType;
+}
+
 impl<T> KDBush<T>
 where
     T: Coords,
@@ -141,6 +383,7 @@ where
             points: points,
             node_size: node_size,
             ids: ids,
+            period: None,
         };
         let l = new_kdb.ids.len();
 
@@ -153,6 +396,23 @@ where
         }
     }
 
+    /// An index from `points` already ordered by `ids`, e.g. deserialized or
+    /// built elsewhere, checked with `validate`.
+    pub fn from_parts(
+        points: Vec<T>,
+        node_size: usize,
+        ids: Vec<usize>,
+    ) -> Result<KDBush<T>, ValidationError> {
+        let index = KDBush {
+            points,
+            node_size,
+            ids,
+            period: None,
+        };
+        index.validate()?;
+        Ok(index)
+    }
+
     pub fn range(
         &self,
         min_x: &T::CoordType,
@@ -165,12 +425,23 @@ where
         axis: Option<i8>,
     ) where
         T: Coords,
-        T::CoordType: Num + PartialOrd,
+        T::CoordType: Num + PartialOrd + Copy,
     {
         if self.points.is_empty() {
             return;
         }
 
+        if let (None, Some((px, py))) = (left, self.period) {
+            // query every part of the box that wraps around the domain
+            let last = self.ids.len() - 1;
+            for (x0, x1) in periodic_intervals(*min_x, *max_x, px) {
+                for (y0, y1) in periodic_intervals(*min_y, *max_y, py) {
+                    self.range(&x0, &y0, &x1, &y1, result, Some(0), Some(last), None);
+                }
+            }
+            return;
+        }
+
         let left = left.unwrap_or(0);
         let right = right.unwrap_or(self.ids.len() - 1);
         let axis = axis.unwrap_or(0);
@@ -239,6 +510,26 @@ where
             return;
         }
 
+        if let (None, Some((px, py))) = (left, self.period) {
+            // query every image of the circle that overlaps the domain,
+            // skipping points already found through another image
+            let last = self.ids.len() - 1;
+            let mut found = vec![false; self.ids.len()];
+            for qx in periodic_images(qx, r, px) {
+                for qy in periodic_images(qy, r, py) {
+                    let mut image = vec![];
+                    self.within(qx, qy, r, &mut image, Some(0), Some(last), None);
+                    for id in image {
+                        if !found[id] {
+                            found[id] = true;
+                            result.push(id);
+                        }
+                    }
+                }
+            }
+            return;
+        }
+
         let left = left.unwrap_or(0);
         let right = right.unwrap_or(self.ids.len() - 1);
         let axis = axis.unwrap_or(0);
@@ -290,6 +581,498 @@ where
         }
     }
 
+    /// Ids of all points inside a polygon, given as rings of `(x, y)`
+    /// vertices with the outer ring first and holes after it. Rings may be
+    /// open or closed. The bounding box of the outer ring is searched with
+    /// `range` and the points in it are tested with the even-odd rule.
+    pub fn within_polygon(&self, polygon: &[Vec<(T::CoordType, T::CoordType)>]) -> Vec<TIndex>
+    where
+        T::CoordType: Signed + PartialOrd + Copy,
+    {
+        let mut result = vec![];
+        let outer = match polygon.first() {
+            Some(ring) if !ring.is_empty() => ring,
+            _ => return result,
+        };
+        let (mut min_x, mut min_y) = outer[0];
+        let (mut max_x, mut max_y) = outer[0];
+        for &(x, y) in outer {
+            min_x = if x < min_x { x } else { min_x };
+            min_y = if y < min_y { y } else { min_y };
+            max_x = if x > max_x { x } else { max_x };
+            max_y = if y > max_y { y } else { max_y };
+        }
+        self.range(
+            &min_x,
+            &min_y,
+            &max_x,
+            &max_y,
+            &mut result,
+            None,
+            None,
+            None,
+        );
+        result.retain(|&id| {
+            let p = &self.points[id];
+            polygon_contains(polygon, p.get_x(), p.get_y())
+        });
+        result
+    }
+
+    /// Makes the domain `[0, px) x [0, py)` wrap around at its edges, so
+    /// `range`, `within` and `nearest` find points across the boundary and
+    /// measure distances to the closest periodic image. All points have to lie
+    /// inside the domain and both periods have to be positive.
+    pub fn with_period(
+        mut self,
+        px: T::CoordType,
+        py: T::CoordType,
+    ) -> Result<KDBush<T>, ValidationError> {
+        self.period = Some((px, py));
+        self.validate_period()?;
+        Ok(self)
+    }
+
+    /// The size of the periodic domain set with `with_period`.
+    pub fn period(&self) -> Option<(T::CoordType, T::CoordType)>
+    where
+        T::CoordType: Copy,
+    {
+        self.period
+    }
+
+    /// Ids of the points closest to `(qx, qy)`, nearest first, stopping after
+    /// `max_results` points or beyond `max_distance`.
+    pub fn nearest(
+        &self,
+        qx: T::CoordType,
+        qy: T::CoordType,
+        max_results: Option<usize>,
+        max_distance: Option<T::CoordType>,
+    ) -> Vec<TIndex>
+    where
+        T::CoordType: Copy,
+    {
+        let mut result = vec![];
+        if self.ids.is_empty() || max_results == Some(0) {
+            return result;
+        }
+        let root = match self.period {
+            Some((px, py)) => BBox {
+                min_x: num::zero(),
+                min_y: num::zero(),
+                max_x: px,
+                max_y: py,
+            },
+            None => match self.extent() {
+                Some(bbox) => bbox,
+                None => return result,
+            },
+        };
+        let (qx, qy) = match self.period {
+            Some((px, py)) => (wrap(qx, px), wrap(qy, py)),
+            None => (qx, qy),
+        };
+        let (px, py) = match self.period {
+            Some((px, py)) => (Some(px), Some(py)),
+            None => (None, None),
+        };
+        let sq_box_dist = |bbox: &BBox<T::CoordType>| {
+            let dx = axis_dist(qx, bbox.min_x, bbox.max_x, px);
+            let dy = axis_dist(qy, bbox.min_y, bbox.max_y, py);
+            dx * dx + dy * dy
+        };
+        let sq_point_dist = |id: TIndex| {
+            let p = &self.points[id];
+            let dx = axis_dist(qx, p.get_x(), p.get_x(), px);
+            let dy = axis_dist(qy, p.get_y(), p.get_y(), py);
+            dx * dx + dy * dy
+        };
+        let max_d2 = max_distance.map(|d| d * d);
+
+        let mut q = BinaryHeap::new();
+        q.push(Scored(
+            sq_box_dist(&root),
+            Nearest::Node(0, self.ids.len() - 1, 0, root),
+        ));
+        while let Some(Scored(d2, entry)) = q.pop() {
+            if max_d2.is_some_and(|max_d2| d2 > max_d2) {
+                break;
+            }
+            match entry {
+                Nearest::Point(id) => {
+                    result.push(id);
+                    if max_results.is_some_and(|n| result.len() == n) {
+                        break;
+                    }
+                }
+                Nearest::Node(left, right, _, _) if right - left <= self.node_size => {
+                    for i in left..right + 1 {
+                        q.push(Scored(
+                            sq_point_dist(self.ids[i]),
+                            Nearest::Point(self.ids[i]),
+                        ));
+                    }
+                }
+                Nearest::Node(left, right, axis, bbox) => {
+                    let m: TIndex = (left + right) >> 1;
+                    let p = &self.points[self.ids[m]];
+                    q.push(Scored(
+                        sq_point_dist(self.ids[m]),
+                        Nearest::Point(self.ids[m]),
+                    ));
+                    let (left_bbox, right_bbox) = bbox.split(axis, p.get_x(), p.get_y());
+                    let next_axis = (axis + 1) % 2;
+                    q.push(Scored(
+                        sq_box_dist(&left_bbox),
+                        Nearest::Node(left, m - 1, next_axis, left_bbox),
+                    ));
+                    q.push(Scored(
+                        sq_box_dist(&right_bbox),
+                        Nearest::Node(m + 1, right, next_axis, right_bbox),
+                    ));
+                }
+            }
+        }
+        result
+    }
+
+    /// Walks all implicit nodes of the tree depth first. The bounding box of
+    /// the root is the extent of the points, children split it at the median.
+    pub fn nodes(&self) -> Nodes<'_, T>
+    where
+        T::CoordType: Copy,
+    {
+        match self.extent() {
+            Some(bbox) => self.nodes_in(bbox),
+            None => Nodes {
+                index: self,
+                stack: vec![],
+            },
+        }
+    }
+
+    /// Like `nodes`, but starting from `bbox` as the bounding box of the root.
+    pub fn nodes_in(&self, bbox: BBox<T::CoordType>) -> Nodes<'_, T>
+    where
+        T::CoordType: Copy,
+    {
+        let mut stack = vec![];
+        if !self.ids.is_empty() {
+            stack.push((0, self.ids.len() - 1, 0, 0, bbox));
+        }
+        Nodes { index: self, stack }
+    }
+
+    pub fn stats(&self) -> TreeStats
+    where
+        T::CoordType: Copy,
+    {
+        let mut stats = TreeStats {
+            depth: 0,
+            nodes: 0,
+            leaves: 0,
+            leaf_fill: vec![0; self.node_size + 2],
+            memory: std::mem::size_of::<Self>()
+                + self.points.capacity() * std::mem::size_of::<T>()
+                + self.ids.capacity() * std::mem::size_of::<TIndex>(),
+        };
+        for node in self.nodes() {
+            stats.nodes += 1;
+            stats.depth = usize::max(stats.depth, node.depth);
+            if node.leaf {
+                stats.leaves += 1;
+                stats.leaf_fill[node.right - node.left + 1] += 1;
+            }
+        }
+        stats
+    }
+
+    fn extent(&self) -> Option<BBox<T::CoordType>>
+    where
+        T::CoordType: Copy,
+    {
+        let first = self.points.first()?;
+        let bbox = BBox {
+            min_x: first.get_x(),
+            min_y: first.get_y(),
+            max_x: first.get_x(),
+            max_y: first.get_y(),
+        };
+        Some(self.points.iter().fold(bbox, |mut bbox, p| {
+            let (x, y) = (p.get_x(), p.get_y());
+            if x < bbox.min_x {
+                bbox.min_x = x;
+            }
+            if x > bbox.max_x {
+                bbox.max_x = x;
+            }
+            if y < bbox.min_y {
+                bbox.min_y = y;
+            }
+            if y > bbox.max_y {
+                bbox.max_y = y;
+            }
+            bbox
+        }))
+    }
+
+    /// Checks that `ids` is a permutation of the point indexes, that every
+    /// node is split on its median the way `sort_kd` leaves it and that all
+    /// points lie inside the period, reporting the first violation found.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.ids.len() != self.points.len() {
+            return Err(ValidationError::LengthMismatch {
+                points: self.points.len(),
+                ids: self.ids.len(),
+            });
+        }
+        if self.node_size == 0 {
+            return Err(ValidationError::InvalidNodeSize(self.node_size));
+        }
+
+        let mut seen = vec![false; self.ids.len()];
+        for (position, &id) in self.ids.iter().enumerate() {
+            if id >= seen.len() || seen[id] {
+                return Err(ValidationError::NotAPermutation { position, id });
+            }
+            seen[id] = true;
+        }
+
+        match self.ids.len() {
+            0 => Ok(()),
+            l => self.validate_node(0, l - 1, 0),
+        }?;
+        self.validate_period()
+    }
+
+    fn validate_period(&self) -> Result<(), ValidationError> {
+        let (px, py) = match &self.period {
+            Some(period) => period,
+            None => return Ok(()),
+        };
+        let zero = &num::zero();
+        if !(px > zero && py > zero) {
+            return Err(ValidationError::InvalidPeriod);
+        }
+        match self.points.iter().position(|p| {
+            let (x, y) = (&p.get_x(), &p.get_y());
+            !(x >= zero && x < px && y >= zero && y < py)
+        }) {
+            Some(id) => Err(ValidationError::OutsidePeriod { id }),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_node(&self, left: TIndex, right: TIndex, axis: u8) -> Result<(), ValidationError> {
+        if right - left <= self.node_size {
+            return Ok(());
+        }
+        let m: TIndex = (left + right) >> 1;
+        let coord_i = axis as i8;
+        let t = self.points[self.ids[m]].get(coord_i);
+
+        let misplaced = (left..m)
+            .find(|&i| self.points[self.ids[i]].get(coord_i) > t)
+            .or_else(|| (m + 1..right + 1).find(|&i| self.points[self.ids[i]].get(coord_i) < t));
+        if let Some(index) = misplaced {
+            return Err(ValidationError::SplitOrder {
+                left,
+                right,
+                axis,
+                index,
+            });
+        }
+
+        self.validate_node(left, m - 1, (axis + 1) % 2)?;
+        self.validate_node(m + 1, right, (axis + 1) % 2)
+    }
+
+    /// Precomputes the maximum of `score` over every node of the tree.
+    pub fn score_bounds<F, S>(&self, score: F) -> ScoreBounds<F, S>
+    where
+        F: Fn(&T) -> S,
+        S: PartialOrd + Copy,
+    {
+        let mut bounds: Vec<S> = self.ids.iter().map(|&id| score(&self.points[id])).collect();
+        if !bounds.is_empty() {
+            self.fill_score_bounds(&mut bounds, 0, self.ids.len() - 1);
+        }
+        ScoreBounds {
+            score,
+            bounds,
+            node_size: self.node_size,
+        }
+    }
+
+    fn fill_score_bounds<S>(&self, bounds: &mut Vec<S>, left: TIndex, right: TIndex) -> S
+    where
+        S: PartialOrd + Copy,
+    {
+        let max = |a: S, b: S| if b > a { b } else { a };
+        if right - left <= self.node_size {
+            let m = bounds[left + 1..right + 1]
+                .iter()
+                .fold(bounds[left], |a, &b| max(a, b));
+            bounds[left] = m;
+            return m;
+        }
+        let m: TIndex = (left + right) >> 1;
+        let l = self.fill_score_bounds(bounds, left, m - 1);
+        let r = self.fill_score_bounds(bounds, m + 1, right);
+        bounds[m] = max(bounds[m], max(l, r));
+        bounds[m]
+    }
+
+    /// Ids of the `k` highest scoring points inside the box, best first.
+    /// `scores` have to be built from this index.
+    pub fn range_top_k<F, S>(
+        &self,
+        min_x: &T::CoordType,
+        min_y: &T::CoordType,
+        max_x: &T::CoordType,
+        max_y: &T::CoordType,
+        k: usize,
+        scores: &ScoreBounds<F, S>,
+    ) -> Result<Vec<TIndex>, ValidationError>
+    where
+        T::CoordType: Copy,
+        F: Fn(&T) -> S,
+        S: PartialOrd + Copy,
+    {
+        // with a period, every part of the box that wraps around the domain
+        let (xs, ys) = match self.period {
+            Some((px, py)) => (
+                periodic_intervals(*min_x, *max_x, px),
+                periodic_intervals(*min_y, *max_y, py),
+            ),
+            None => (vec![(*min_x, *max_x)], vec![(*min_y, *max_y)]),
+        };
+        let inside = |p: &T| {
+            let (x, y) = (p.get_x(), p.get_y());
+            xs.iter().any(|&(x0, x1)| x >= x0 && x <= x1)
+                && ys.iter().any(|&(y0, y1)| y >= y0 && y <= y1)
+        };
+        let visit_left = |p: &T, axis: u8| {
+            if axis == 0 {
+                xs.iter().any(|&(x0, _)| x0 <= p.get_x())
+            } else {
+                ys.iter().any(|&(y0, _)| y0 <= p.get_y())
+            }
+        };
+        let visit_right = |p: &T, axis: u8| {
+            if axis == 0 {
+                xs.iter().any(|&(_, x1)| x1 >= p.get_x())
+            } else {
+                ys.iter().any(|&(_, y1)| y1 >= p.get_y())
+            }
+        };
+        self.top_k(k, scores, &inside, &visit_left, &visit_right)
+    }
+
+    /// Ids of the `k` highest scoring points within radius `r`, best first.
+    /// `scores` have to be built from this index.
+    pub fn within_top_k<F, S>(
+        &self,
+        qx: T::CoordType,
+        qy: T::CoordType,
+        r: T::CoordType,
+        k: usize,
+        scores: &ScoreBounds<F, S>,
+    ) -> Result<Vec<TIndex>, ValidationError>
+    where
+        T::CoordType: Copy,
+        F: Fn(&T) -> S,
+        S: PartialOrd + Copy,
+    {
+        // with a period, every image of the circle that overlaps the domain
+        let (xs, ys) = match self.period {
+            Some((px, py)) => (periodic_images(qx, r, px), periodic_images(qy, r, py)),
+            None => (vec![qx], vec![qy]),
+        };
+        let r2 = r * r;
+        let inside = |p: &T| {
+            xs.iter().any(|&qx| {
+                ys.iter()
+                    .any(|&qy| Self::sq_dist(p.get_x(), p.get_y(), qx, qy) <= r2)
+            })
+        };
+        let visit_left = |p: &T, axis: u8| {
+            if axis == 0 {
+                xs.iter().any(|&qx| qx - r <= p.get_x())
+            } else {
+                ys.iter().any(|&qy| qy - r <= p.get_y())
+            }
+        };
+        let visit_right = |p: &T, axis: u8| {
+            if axis == 0 {
+                xs.iter().any(|&qx| qx + r >= p.get_x())
+            } else {
+                ys.iter().any(|&qy| qy + r >= p.get_y())
+            }
+        };
+        self.top_k(k, scores, &inside, &visit_left, &visit_right)
+    }
+
+    fn top_k<F, S>(
+        &self,
+        k: usize,
+        scores: &ScoreBounds<F, S>,
+        inside: &dyn Fn(&T) -> bool,
+        visit_left: &dyn Fn(&T, u8) -> bool,
+        visit_right: &dyn Fn(&T, u8) -> bool,
+    ) -> Result<Vec<TIndex>, ValidationError>
+    where
+        F: Fn(&T) -> S,
+        S: PartialOrd + Copy,
+    {
+        scores.check(self.ids.len(), self.node_size)?;
+        let mut heap = BinaryHeap::new();
+        if k == 0 || self.ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut stack = vec![(0, self.ids.len() - 1, 0)];
+        while let Some((left, right, axis)) = stack.pop() {
+            let leaf = right - left <= self.node_size;
+            let bound = scores.node_bound(left, right, self.node_size);
+            if heap.len() == k && heap.peek().is_some_and(|w: &Scored<S>| bound <= w.0) {
+                continue;
+            }
+
+            let mut offer = |i: TIndex| {
+                let p = &self.points[self.ids[i]];
+                if !inside(p) {
+                    return;
+                }
+                let score = scores.score(p);
+                if heap.len() < k {
+                    heap.push(Scored(score, self.ids[i]));
+                } else if heap.peek().is_some_and(|w| score > w.0) {
+                    heap.pop();
+                    heap.push(Scored(score, self.ids[i]));
+                }
+            };
+
+            if leaf {
+                (left..right + 1).for_each(&mut offer);
+                continue;
+            }
+
+            let m: TIndex = (left + right) >> 1;
+            offer(m);
+            let p = &self.points[self.ids[m]];
+            if visit_right(p, axis) {
+                stack.push((m + 1, right, (axis + 1) % 2));
+            }
+            if visit_left(p, axis) {
+                stack.push((left, m - 1, (axis + 1) % 2));
+            }
+        }
+
+        Ok(heap.into_sorted_vec().into_iter().map(|s| s.1).collect())
+    }
+
     fn sort_kd(&mut self, left: TIndex, right: TIndex, axis: u8) {
         if right - left <= self.node_size {
             return;
@@ -379,3 +1162,101 @@ where
         dx.clone() * dx + dy.clone() * dy
     }
 }
+
+// Even-odd test of a point against all rings of a polygon.
+fn polygon_contains<T>(polygon: &[Vec<(T, T)>], x: T, y: T) -> bool
+where
+    T: Signed + PartialOrd + Copy,
+{
+    let mut inside = false;
+    for ring in polygon {
+        for (i, &(xi, yi)) in ring.iter().enumerate() {
+            let (xj, yj) = ring[(i + 1) % ring.len()];
+            if (yi > y) == (yj > y) {
+                continue;
+            }
+            // does the edge cross the ray going right from the point,
+            // x < xi + (xj - xi) * (y - yi) / (yj - yi) without the division
+            let lhs = (x - xi) * (yj - yi);
+            let rhs = (xj - xi) * (y - yi);
+            if (yj > yi && lhs < rhs) || (yj < yi && lhs > rhs) {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+// `v` moved into `[0, p)`
+fn wrap<T>(v: T, p: T) -> T
+where
+    T: Num + PartialOrd + Copy,
+{
+    let v = v % p;
+    if v < T::zero() {
+        v + p
+    } else {
+        v
+    }
+}
+
+// The parts of `[min, max]` inside the periodic domain `[0, p)`.
+fn periodic_intervals<T>(min: T, max: T, p: T) -> Vec<(T, T)>
+where
+    T: Num + PartialOrd + Copy,
+{
+    if max - min >= p {
+        return vec![(T::zero(), p)];
+    }
+    let lo = wrap(min, p);
+    let hi = lo + (max - min);
+    if hi < p {
+        vec![(lo, hi)]
+    } else {
+        vec![(lo, p), (T::zero(), hi - p)]
+    }
+}
+
+// The images of a query coordinate whose radius `r` reaches into `[0, p)`.
+fn periodic_images<T>(q: T, r: T, p: T) -> Vec<T>
+where
+    T: Num + PartialOrd + Copy,
+{
+    let q = wrap(q, p);
+    let mut images = vec![q];
+    let mut image = q - p;
+    while image + r >= T::zero() {
+        images.push(image);
+        image = image - p;
+    }
+    let mut image = q + p;
+    while image - r < p {
+        images.push(image);
+        image = image + p;
+    }
+    images
+}
+
+// Distance from `q` to the interval `[a, b]`, to its nearest image if the
+// axis has period `p`.
+fn axis_dist<T>(q: T, a: T, b: T, p: Option<T>) -> T
+where
+    T: Num + PartialOrd + Copy,
+{
+    let direct = |q: T| {
+        if q < a {
+            a - q
+        } else if q > b {
+            q - b
+        } else {
+            T::zero()
+        }
+    };
+    let d = direct(q);
+    match p {
+        Some(p) => [direct(q - p), direct(q + p)]
+            .iter()
+            .fold(d, |d, &e| if e < d { e } else { d }),
+        None => d,
+    }
+}
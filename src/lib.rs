@@ -1,32 +1,990 @@
+pub mod csv_io;
+pub mod dataset;
+pub mod flatbush;
+pub mod geo;
+pub mod geojson;
 pub mod geokdbush;
 pub mod kdbush;
+pub mod reverse_geocode;
 
 extern crate flate2;
 
 #[cfg(test)]
 mod tests {
-    use crate::geokdbush::around;
-    use crate::kdbush::{City, KDBush};
+    use crate::csv_io::{write_results, CsvLoader};
+    use crate::dataset::{load_cities_json_gz, GeoNames};
+    use crate::flatbush::Flatbush;
+    use crate::geo::geodesy::{
+        along_track_distance, bearing, cross_track_distance, destination, interpolate, midpoint,
+        rhumb_distance,
+    };
+    use crate::geojson::{read_feature_collection, results_to_geojson};
+    use crate::geokdbush::{
+        along_route, around, around_ranked, around_sector, around_to_geojson, around_with_distance,
+        around_with_stats, distance, distance_ellipsoidal, nearest_iter, nodes_to_geojson,
+        range_geo, within_km, within_polygon, within_radius, Formula, GeoQuery, QueryStats, Units,
+    };
+    use crate::kdbush::{BBox, City, Coords, KDBush, RawCoord};
+    use crate::reverse_geocode::ReverseGeocoder;
 
+    use std::collections::HashMap;
     use std::error::Error;
-    use std::fs::File;
-    use std::io::prelude::*;
     use std::path::Path;
 
-    use flate2::read::GzDecoder;
+    fn serialize_cities<P: AsRef<Path>>(path: P) -> Result<KDBush<City>, Box<dyn Error>> {
+        let cities = load_cities_json_gz(path)?;
+        Ok(KDBush::new(cities, 64)?)
+    }
+
+    fn grid() -> KDBush<RawCoord<f64>> {
+        let points = (0..400)
+            .map(|i| RawCoord(f64::from(i % 20) - 10.0, f64::from(i / 20) - 10.0))
+            .collect();
+        KDBush::new(points, 8).unwrap()
+    }
+
+    #[test]
+    fn debug_geojson() {
+        let index = grid();
+        let nodes = nodes_to_geojson(&index);
+        let features = nodes["features"].as_array().unwrap();
+        assert_eq!(features.len(), index.nodes().count());
+        assert_eq!(
+            features[0]["geometry"]["coordinates"][0][2],
+            serde_json::json!([180.0, 90.0])
+        );
+
+        let traversal = around_to_geojson(
+            &index,
+            0.2,
+            0.1,
+            Some(3),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        let features = traversal["features"].as_array().unwrap();
+        assert_eq!(features[0]["properties"]["order"], 0);
+        assert_eq!(features[0]["properties"]["right"], 399);
+        let points: Vec<_> = features
+            .iter()
+            .filter(|f| f["geometry"]["type"] == "Point")
+            .collect();
+        assert_eq!(points.len(), 4);
+        assert_eq!(points[0]["properties"]["query"], true);
+        assert_eq!(
+            points[1]["geometry"]["coordinates"],
+            serde_json::json!([0.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn distance_units() {
+        // Amsterdam to Paris
+        let km: f64 = distance(
+            4.8897,
+            52.374,
+            2.3522,
+            48.8566,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        assert!((km - 430.0).abs() < 1.0);
+        let m: f64 = distance(
+            4.8897,
+            52.374,
+            2.3522,
+            48.8566,
+            Units::Meters,
+            Formula::Spherical,
+        );
+        assert!((m - km * 1000.0).abs() < 1e-6);
+        let mi: f64 = distance(
+            4.8897,
+            52.374,
+            2.3522,
+            48.8566,
+            Units::Miles,
+            Formula::Spherical,
+        );
+        assert!((mi * 1.609_344 - km).abs() < 1e-9);
+        let nmi: f64 = distance(
+            4.8897,
+            52.374,
+            2.3522,
+            48.8566,
+            Units::NauticalMiles,
+            Formula::Spherical,
+        );
+        assert!((nmi * 1.852 - km).abs() < 1e-9);
+        let unit = distance(
+            0.0,
+            0.0,
+            90.0,
+            0.0,
+            Units::SphereRadius(1.0),
+            Formula::Spherical,
+        );
+        assert!((unit - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+
+        let index = grid();
+        let near = around(
+            &index,
+            0.0,
+            0.0,
+            None,
+            Some(112_000.0),
+            &None,
+            Units::Meters,
+            Formula::Spherical,
+        );
+        assert_eq!(near.len(), 5);
+    }
+
+    #[test]
+    fn around_returns_distances() {
+        let index = grid();
+        let near = around_with_distance(
+            &index,
+            0.3,
+            0.2,
+            Some(6),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Haversine,
+        );
+        assert_eq!(near.len(), 6);
+        assert_eq!(near[0].0, 210);
+        for &(id, p, dist) in &near {
+            assert!(std::ptr::eq(p, &index.points[id]));
+            assert_eq!(
+                dist,
+                distance(0.3, 0.2, p.0, p.1, Units::Kilometers, Formula::Haversine)
+            );
+        }
+        assert!(near.windows(2).all(|w| w[0].2 <= w[1].2));
+    }
+
+    #[test]
+    fn nearest_iter_is_lazy_and_ordered() {
+        let index = grid();
+        let all: Vec<_> = nearest_iter(&index, 0.3, 0.2).collect();
+        assert_eq!(all.len(), index.points.len());
+        assert!(all.windows(2).all(|w| w[0].2 <= w[1].2));
+
+        let near = around_with_distance(
+            &index,
+            0.3,
+            0.2,
+            Some(6),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        let first: Vec<_> = nearest_iter(&index, 0.3, 0.2).take(6).collect();
+        let key = |v: &[(usize, &RawCoord<f64>, f64)]| -> Vec<(usize, f64)> {
+            v.iter().map(|&(id, _, d)| (id, d)).collect()
+        };
+        assert_eq!(key(&first), key(&near));
+
+        let empty: KDBush<RawCoord<f64>> = KDBush::new(vec![], 8).unwrap();
+        assert!(nearest_iter(&empty, 0.0, 0.0).next().is_none());
+    }
+
+    #[test]
+    fn query_stats() {
+        let index = grid();
+        let (near, stats) = around_with_stats(
+            &index,
+            0.3,
+            0.2,
+            Some(3),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        assert_eq!(near.len(), 3);
+        assert!(stats.nodes_visited > 0);
+        assert!(stats.points_tested >= 3 && stats.points_tested < index.points.len() / 4);
+        assert!(stats.distance_evals > stats.points_tested);
+        assert!(stats.heap_peak > 0);
+
+        let mut total = QueryStats::default();
+        total += stats;
+        total += stats;
+        assert_eq!(total.nodes_visited, 2 * stats.nodes_visited);
+        assert_eq!(total.heap_peak, stats.heap_peak);
+    }
+
+    #[test]
+    fn geo_query_builder() {
+        let index = grid();
+        let near = GeoQuery::new(0.3, 0.2).limit(6).run(&index);
+        let expected = around_with_distance(
+            &index,
+            0.3,
+            0.2,
+            Some(6),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        let ids = |v: &[(usize, &RawCoord<f64>, f64)]| -> Vec<usize> {
+            v.iter().map(|&(id, _, _)| id).collect()
+        };
+        assert_eq!(ids(&near), ids(&expected));
+
+        // the filter borrows local state mutably
+        let mut tested = 0;
+        let min_lng = 2.0;
+        let east = GeoQuery::new(0.3, 0.2)
+            .max_distance(500.0)
+            .filter(|p: &RawCoord<f64>| {
+                tested += 1;
+                p.0 >= min_lng
+            })
+            .run(&index);
+        assert!(tested > east.len());
+        assert!(east.iter().all(|&(_, p, d)| p.0 >= min_lng && d <= 500.0));
+
+        let within = GeoQuery::new(0.3, 0.2)
+            .max_distance(500.0)
+            .units(Units::Meters)
+            .count(&index);
+        assert_eq!(within, 0);
+        assert_eq!(GeoQuery::new(0.3, 0.2).count(&index), index.points.len());
+
+        assert!(GeoQuery::new(0.3, 0.2).limit(0).run(&index).is_empty());
+        let none = around(
+            &index,
+            0.3,
+            0.2,
+            Some(0),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn sector_query() {
+        let points: Vec<RawCoord<f64>> = (0..120 * 60)
+            .map(|i| {
+                RawCoord(
+                    f64::from(i % 120) * 3.0 - 178.5,
+                    f64::from(i / 120) * 3.0 - 88.5,
+                )
+            })
+            .collect();
+        let index = KDBush::new(points, 8).unwrap();
+        let rad = std::f64::consts::PI / 180.0;
+        let bearing_to = |lng: f64, lat: f64, p: &RawCoord<f64>| {
+            let dlng = (p.0 - lng) * rad;
+            let y = dlng.sin() * (p.1 * rad).cos();
+            let x = (lat * rad).cos() * (p.1 * rad).sin()
+                - (lat * rad).sin() * (p.1 * rad).cos() * dlng.cos();
+            y.atan2(x) / rad
+        };
+        // (lng, lat, bearing, half_angle, max_dist) heading north, across the
+        // antimeridian and with a window wider than a half plane
+        let queries = [
+            (4.9, 52.4, 350.0, 20.0, 2500.0),
+            (179.0, -16.5, 90.0, 30.0, 1500.0),
+            (-70.0, 10.0, 200.0, 120.0, 1000.0),
+        ];
+        for &(lng, lat, bearing, half_angle, max_dist) in &queries {
+            let found = around_sector(
+                &index,
+                lng,
+                lat,
+                bearing,
+                half_angle,
+                max_dist,
+                Units::Kilometers,
+                Formula::Spherical,
+            );
+            assert!(!found.is_empty());
+            assert!(found.windows(2).all(|w| w[0].2 <= w[1].2));
+
+            let mut expected: Vec<usize> = (0..index.points.len())
+                .filter(|&id| {
+                    let p = &index.points[id];
+                    let off = (bearing_to(lng, lat, p) - bearing).rem_euclid(360.0);
+                    let off = if off > 180.0 { 360.0 - off } else { off };
+                    let d = distance(lng, lat, p.0, p.1, Units::Kilometers, Formula::Spherical);
+                    d <= max_dist && off <= half_angle
+                })
+                .collect();
+            let mut ids: Vec<usize> = found.iter().map(|&(id, _, _)| id).collect();
+            ids.sort();
+            expected.sort();
+            assert_eq!(ids, expected);
+
+            let max_miles = max_dist / 1.609_344;
+            let in_miles = around_sector(
+                &index,
+                lng,
+                lat,
+                bearing,
+                half_angle,
+                max_miles,
+                Units::Miles,
+                Formula::Haversine,
+            );
+            assert_eq!(in_miles.len(), found.len());
+            let narrow = around_sector(
+                &index,
+                lng,
+                lat,
+                bearing,
+                -half_angle,
+                max_dist,
+                Units::Kilometers,
+                Formula::Spherical,
+            );
+            assert!(narrow.is_empty());
+        }
+    }
+
+    #[test]
+    fn polygon_across_antimeridian() {
+        let points: Vec<RawCoord<f64>> = (0..120 * 60)
+            .map(|i| {
+                RawCoord(
+                    f64::from(i % 120) * 3.0 - 178.5,
+                    f64::from(i / 120) * 3.0 - 88.5,
+                )
+            })
+            .collect();
+        let index = KDBush::new(points, 8).unwrap();
+        // the edges along 10 degrees are great circles, bulging to about 10.15
+        let polygon = vec![
+            vec![
+                (170.0, -10.0),
+                (-170.0, -10.0),
+                (-170.0, 10.0),
+                (170.0, 10.0),
+            ],
+            vec![
+                (178.0, -4.0),
+                (-178.0, -4.0),
+                (-178.0, 4.0),
+                (178.0, 4.0),
+                (178.0, -4.0),
+            ],
+        ];
+        let mut ids = within_polygon(&index, &polygon);
+        ids.sort();
+        let mut expected: Vec<usize> = (0..index.points.len())
+            .filter(|&id| {
+                let p = &index.points[id];
+                let in_outer = (p.0 > 170.0 || p.0 < -170.0) && p.1.abs() < 10.0;
+                let in_hole = (p.0 > 178.0 || p.0 < -178.0) && p.1.abs() < 4.0;
+                in_outer && !in_hole
+            })
+            .collect();
+        expected.sort();
+        assert_eq!(ids.len(), 32);
+        assert_eq!(ids, expected);
+
+        // a long edge along a parallel is not a great circle: near 60 degrees
+        // the arc from 0 to 90 east runs north of 65 in the middle
+        let polygon = vec![vec![(0.0, 50.0), (90.0, 50.0), (90.0, 60.0), (0.0, 60.0)]];
+        let ids = within_polygon(&index, &polygon);
+        assert!(ids
+            .iter()
+            .any(|&id| index.points[id].1 > 60.0 && index.points[id].0 == 46.5));
+    }
+
+    #[test]
+    fn corridor_along_route() {
+        let index = grid();
+        let route = [(-5.0, 0.0), (5.0, 0.0), (5.0, 5.0)];
+        let found = along_route(&index, &route, 60.0, Units::Kilometers, Formula::Spherical);
+        // the points on the route itself, a degree is about 111 km
+        assert_eq!(found.len(), 16);
+        assert!(found.windows(2).all(|w| w[0].along <= w[1].along));
+        let degree = distance(0.0, 0.0, 1.0, 0.0, Units::Kilometers, Formula::Spherical);
+        for (i, m) in found.iter().enumerate() {
+            let p = &index.points[m.id];
+            assert!(m.distance < 1e-6);
+            assert!((m.along - degree * i as f64).abs() < 1e-6);
+            assert_eq!(
+                (p.0, p.1),
+                if i <= 10 {
+                    (i as f64 - 5.0, 0.0)
+                } else {
+                    (5.0, i as f64 - 10.0)
+                }
+            );
+        }
+
+        // within 1.35 degrees: the rows next to the first leg, the columns
+        // next to the second and four more beyond the ends and the corner
+        let wide = along_route(&index, &route, 150.0, Units::Kilometers, Formula::Spherical);
+        assert!(wide.iter().all(|m| m.distance <= 150.0));
+        assert_eq!(wide.len(), 3 * 11 + 3 * 4 + 4);
+
+        let geodesic = along_route(&index, &route, 150e3, Units::Meters, Formula::Ellipsoidal);
+        assert_eq!(geodesic.len(), wide.len());
+        let end = geodesic
+            .iter()
+            .find(|m| index.points[m.id].0 == 5.0 && index.points[m.id].1 == 5.0);
+        let expected = distance(-5.0, 0.0, 5.0, 0.0, Units::Meters, Formula::Ellipsoidal)
+            + distance(5.0, 0.0, 5.0, 5.0, Units::Meters, Formula::Ellipsoidal);
+        assert!((end.unwrap().along - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn geodesy_helpers() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+        let km = Units::Kilometers;
+        assert!(close(bearing(0.0, 0.0, 1.0, 0.0), 90.0));
+        assert!(close(bearing(0.0, 0.0, 0.0, -1.0), 180.0));
+        assert!(close(bearing(0.0, 0.0, -1.0, 0.0), 270.0));
+
+        // to Paris and back
+        let (lng, lat) = (-0.1278, 51.5074);
+        let (lng2, lat2) = (2.3522, 48.8566);
+        let d = distance(lng, lat, lng2, lat2, km, Formula::Haversine);
+        let (dlng, dlat) = destination(lng, lat, d, bearing(lng, lat, lng2, lat2), km);
+        assert!(close(dlng, lng2) && close(dlat, lat2));
+        let (dlng, dlat) = destination(179.5, 0.0, d, 90.0, km);
+        assert!(dlng < -175.0 && close(dlat, 0.0));
+
+        let (mlng, mlat) = midpoint(lng, lat, lng2, lat2);
+        let (ilng, ilat) = interpolate(lng, lat, lng2, lat2, 0.5);
+        assert!(close(mlng, ilng) && close(mlat, ilat));
+        let half = distance(lng, lat, mlng, mlat, km, Formula::Haversine);
+        assert!(close(half, d / 2.0));
+        assert_eq!(interpolate(lng, lat, lng2, lat2, 0.0), (lng, lat));
+
+        // a point a degree north of a path going east lies to its left
+        let degree = distance(0.0, 0.0, 0.0, 1.0, km, Formula::Haversine);
+        let xt = cross_track_distance(0.5, 1.0, 0.0, 0.0, 1.0, 0.0, km);
+        assert!(close(xt, -degree));
+        let at = along_track_distance(0.5, 1.0, 0.0, 0.0, 1.0, 0.0, km);
+        assert!(close(at, degree / 2.0));
+        let behind = along_track_distance(-0.5, -1.0, 0.0, 0.0, 1.0, 0.0, km);
+        assert!(close(behind, -degree / 2.0));
+
+        // rhumb lines follow meridians and the equator, elsewhere they are longer
+        assert!(close(
+            rhumb_distance(0.0, 0.0, 10.0, 0.0, km),
+            10.0 * degree
+        ));
+        assert!(close(
+            rhumb_distance(5.0, -20.0, 5.0, 30.0, km),
+            50.0 * degree
+        ));
+        assert!(
+            rhumb_distance(lng, lat, -74.0, 40.7, km)
+                > distance(lng, lat, -74.0, 40.7, km, Formula::Haversine) + 100.0
+        );
+    }
+
+    #[test]
+    fn ranked_by_gravity() {
+        let index = grid();
+        let population = |p: &RawCoord<f64>| (p.0 * 7.0 + p.1 * 13.0).rem_euclid(50.0) + 1.0;
+        let bounds = index.score_bounds(population);
+        let gravity = |population: f64, d: f64| population / ((d + 10.0) * (d + 10.0));
+
+        let ranked = around_ranked(
+            &index,
+            0.3,
+            0.2,
+            5,
+            &bounds,
+            gravity,
+            Units::Kilometers,
+            Formula::Spherical,
+        )
+        .unwrap();
+        let mut expected: Vec<(usize, f64)> = index
+            .points
+            .iter()
+            .enumerate()
+            .map(|(id, p)| {
+                let d = distance(0.3, 0.2, p.0, p.1, Units::Kilometers, Formula::Spherical);
+                (id, gravity(population(p), d))
+            })
+            .collect();
+        expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let ids: Vec<usize> = ranked.iter().map(|&(id, _, _)| id).collect();
+        let expected_ids: Vec<usize> = expected[..5].iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, expected_ids);
+        assert_eq!(ranked[0].2, expected[0].1);
+        let in_metres = around_ranked(
+            &index,
+            0.3,
+            0.2,
+            5,
+            &bounds,
+            |population, d| gravity(population, d / 1000.0),
+            Units::Meters,
+            Formula::Haversine,
+        )
+        .unwrap();
+        let metres_ids: Vec<usize> = in_metres.iter().map(|&(id, _, _)| id).collect();
+        assert_eq!(metres_ids, ids);
+        // the nearest point, (0, 0), is the smallest place around
+        assert!(!ids.contains(&210));
+    }
+
+    #[test]
+    fn haversine_short_distances() {
+        // (lng, lat, dlng, dlat, metres) of pairs a few centimetres apart, the
+        // distances from the Vincenty great circle formula at 50 digits
+        let pairs: [(f64, f64, f64, f64, f64); 4] = [
+            (4.8897, 52.374, 1e-6, 0.0, 0.067_885_111_403_510),
+            (4.8897, 52.374, 0.0, 3e-7, 0.033_358_524_459_891),
+            (-73.9857, 40.7484, 5e-6, -4e-6, 0.612_565_905_306_345),
+            (179.9999999, -16.5, 2e-7, 1e-7, 0.024_048_338_500_037),
+        ];
+        let mut acos_error = 0.0_f64;
+        for &(lng, lat, dlng, dlat, reference) in &pairs {
+            let (lng2, lat2) = (lng + dlng, lat + dlat);
+            let d = distance(lng, lat, lng2, lat2, Units::Meters, Formula::Haversine);
+            assert!((d - reference).abs() < 1e-9, "{} vs {}", d, reference);
+            let d = distance(lng, lat, lng2, lat2, Units::Meters, Formula::Spherical);
+            acos_error = acos_error.max((d - reference).abs());
+        }
+        // the law of cosines is off by millimetres or more at this scale
+        assert!(acos_error > 1e-3);
+
+        // points 10 cm apart along a meridian come back in order
+        let points: Vec<RawCoord<f64>> = (0..50)
+            .map(|i| RawCoord(4.8897, 52.374 + f64::from(i) * 9e-7))
+            .collect();
+        let index = KDBush::new(points, 4).unwrap();
+        let near = around(
+            &index,
+            4.8897,
+            52.374 - 1e-7,
+            Some(20),
+            None,
+            &None,
+            Units::Meters,
+            Formula::Haversine,
+        );
+        let lats: Vec<f64> = near.iter().map(|p| p.1).collect();
+        assert_eq!(
+            lats,
+            index.points[..20].iter().map(|p| p.1).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ellipsoidal_distance() {
+        // Flinders Peak to Buninyong, Vincenty's own test case
+        let d: f64 = distance_ellipsoidal(
+            144.0 + 25.0 / 60.0 + 29.5244 / 3600.0,
+            -(37.0 + 57.0 / 60.0 + 3.7203 / 3600.0),
+            143.0 + 55.0 / 60.0 + 35.3839 / 3600.0,
+            -(37.0 + 39.0 / 60.0 + 10.1561 / 3600.0),
+            Units::Meters,
+        );
+        assert!((d - 54_972.271).abs() < 0.001);
+        assert_eq!(distance_ellipsoidal(5.0, 5.0, 5.0, 5.0, Units::Meters), 0.0);
+        // along the equator the geodesic is an arc of radius a
+        let d: f64 = distance_ellipsoidal(0.0, 0.0, 1.0, 0.0, Units::Kilometers);
+        assert!((d - 6378.137 * std::f64::consts::PI / 180.0).abs() < 1e-9);
+
+        let index = grid();
+        let near = around(
+            &index,
+            0.3,
+            0.2,
+            None,
+            Some(400.0),
+            &None,
+            Units::Kilometers,
+            Formula::Ellipsoidal,
+        );
+        let dists: Vec<f64> = near
+            .iter()
+            .map(|p| distance_ellipsoidal(0.3, 0.2, p.0, p.1, Units::Kilometers))
+            .collect();
+        assert!(dists.windows(2).all(|w| w[0] <= w[1]));
+        let expected = index
+            .points
+            .iter()
+            .filter(|p| distance_ellipsoidal(0.3, 0.2, p.0, p.1, Units::Kilometers) <= 400.0)
+            .count();
+        assert_eq!(near.len(), expected);
+    }
+
+    #[test]
+    fn range_across_antimeridian() {
+        let points = vec![
+            RawCoord(178.4, -18.1), // Suva
+            RawCoord(-179.9, -16.5),
+            RawCoord(-175.2, -21.1), // Nuku'alofa
+            RawCoord(174.8, -41.3),  // Wellington
+            RawCoord(0.0, -18.0),
+            RawCoord(179.0, 89.5),
+        ];
+        let index = KDBush::new(points, 2).unwrap();
+        let sorted = |mut ids: Vec<usize>| {
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(
+            sorted(range_geo(&index, 177.0, -22.0, -175.0, -15.0)),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            sorted(range_geo(&index, 177.0, -22.0, 185.0, -15.0)),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            sorted(range_geo(&index, -183.0, -22.0, -175.0, -15.0)),
+            vec![0, 1, 2]
+        );
+        assert_eq!(range_geo(&index, 170.0, -50.0, 176.0, -15.0), vec![3]);
+        assert_eq!(range_geo(&index, 170.0, 80.0, 180.0, 120.0), vec![5]);
+        assert_eq!(range_geo(&index, -180.0, -100.0, 180.0, 100.0).len(), 6);
+        assert!(range_geo(&index, 0.0, 10.0, 10.0, -10.0).is_empty());
+
+        // both sides of the antimeridian, stored as 180 and as -180
+        let points = vec![
+            RawCoord(180.0, 0.0),
+            RawCoord(-180.0, 1.0),
+            RawCoord(179.0, 0.0),
+            RawCoord(-179.0, 0.0),
+        ];
+        let index = KDBush::new(points, 2).unwrap();
+        assert_eq!(
+            sorted(range_geo(&index, 180.0, -5.0, -178.5, 5.0)),
+            vec![0, 1, 3]
+        );
+        assert_eq!(
+            sorted(range_geo(&index, -180.0, -5.0, -178.5, 5.0)),
+            vec![0, 1, 3]
+        );
+        assert_eq!(
+            sorted(range_geo(&index, 178.5, -5.0, 180.0, 5.0)),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            sorted(range_geo(&index, 178.5, -5.0, -180.0, 5.0)),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn within_km_radius() {
+        let index = grid();
+        let sorted = |mut ids: Vec<usize>| {
+            ids.sort();
+            ids
+        };
+        let brute = |lng: f64, lat: f64, radius: f64, units: Units, formula: Formula| {
+            (0..index.points.len())
+                .filter(|&i| {
+                    let p = &index.points[i];
+                    distance(lng, lat, p.0, p.1, units, formula) <= radius
+                })
+                .collect::<Vec<usize>>()
+        };
+
+        for &(lng, lat, radius) in &[(0.3, 0.2, 250.0), (-9.5, 9.0, 400.0), (5.0, -5.0, 1.0)] {
+            assert_eq!(
+                sorted(within_km(&index, lng, lat, radius)),
+                brute(lng, lat, radius, Units::Kilometers, Formula::Spherical)
+            );
+            let (miles, ellipsoid) = (Units::Miles, Formula::Ellipsoidal);
+            assert_eq!(
+                sorted(within_radius(&index, lng, lat, radius, miles, ellipsoid)),
+                brute(lng, lat, radius, miles, ellipsoid)
+            );
+        }
+        assert_eq!(within_km(&index, 0.0, 0.0, 1.0), vec![210]);
+
+        // caps around the poles and across the antimeridian
+        let points = vec![
+            RawCoord(179.5, 0.0),
+            RawCoord(-179.5, 0.0),
+            RawCoord(0.0, 89.9),
+            RawCoord(180.0, 89.9),
+        ];
+        let index = KDBush::new(points, 2).unwrap();
+        assert_eq!(sorted(within_km(&index, 180.0, 0.0, 60.0)), vec![0, 1]);
+        assert_eq!(sorted(within_km(&index, 90.0, 89.95, 20.0)), vec![2, 3]);
+    }
+
+    #[test]
+    fn flatbush() {
+        // a 10x10 grid of unit tiles with a gap of 1 between them
+        let tiles: Vec<BBox<f64>> = (0..100)
+            .map(|i| {
+                let (x, y) = (f64::from(i % 10) * 2.0, f64::from(i / 10) * 2.0);
+                BBox {
+                    min_x: x,
+                    min_y: y,
+                    max_x: x + 1.0,
+                    max_y: y + 1.0,
+                }
+            })
+            .collect();
+        let index = Flatbush::new(tiles, 4).unwrap();
+        assert_eq!(index.boxes.len(), *index.level_bounds.last().unwrap());
+
+        let mut ids = vec![];
+        index.search(2.5, 2.5, 4.5, 4.5, &mut ids);
+        ids.sort();
+        assert_eq!(ids, vec![11, 12, 21, 22]);
+
+        let mut ids = vec![];
+        index.search_point(6.5, 0.5, &mut ids);
+        assert_eq!(ids, vec![3]);
+        let mut ids = vec![];
+        index.search_point(5.5, 0.5, &mut ids);
+        assert!(ids.is_empty());
+
+        let nearest = index.neighbors(8.5, 7.2, Some(3), None);
+        assert_eq!(nearest[0], 34);
+        assert_eq!(nearest.len(), 3);
+        assert_eq!(index.neighbors(8.5, 7.2, None, Some(0.3)), vec![34]);
+        assert_eq!(index.neighbors(8.5, 7.2, None, None).len(), 100);
+        assert!(index.neighbors(8.5, 7.2, Some(0), None).is_empty());
+
+        let json = serde_json::to_string(&index).unwrap();
+        let copy: Flatbush<BBox<f64>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.neighbors(8.5, 7.2, Some(3), None), nearest);
+
+        assert!(Flatbush::new(Vec::<BBox<f64>>::new(), 1).is_err());
+    }
+
+    #[derive(Coords)]
+    #[coords(crate = "crate")]
+    struct Position {
+        #[coords(x)]
+        lng: f32,
+        #[coords(y)]
+        lat: f32,
+    }
+
+    #[derive(Coords)]
+    #[coords(x = "position.lng", y = "position.lat", ty = "f32", crate = "crate")]
+    struct Vehicle {
+        position: Position,
+    }
+
+    #[derive(Coords)]
+    #[coords(x = "x()", y = "1", ty = "i32", crate = "crate")]
+    struct Tile(i32, i32);
+
+    impl Tile {
+        fn x(&self) -> i32 {
+            self.0 * 2
+        }
+    }
+
+    #[test]
+    fn derive_coords() {
+        let vehicle = Vehicle {
+            position: Position {
+                lng: 4.9,
+                lat: 52.4,
+            },
+        };
+        assert_eq!(vehicle.get_x(), 4.9);
+        assert_eq!(vehicle.get(1), 52.4);
+
+        let tile = Tile(3, 7);
+        assert_eq!((tile.get_x(), tile.get_y()), (6, 7));
+        assert_eq!(tile.get(0), 6);
+    }
+
+    #[test]
+    fn reverse_geocode_lookup() {
+        let city = |name: &str, country: &str, admin_code: &str, lon: f64, lat: f64| City {
+            name: name.to_string(),
+            country: country.to_string(),
+            alt_country: String::new(),
+            muni: String::new(),
+            muni_sub: String::new(),
+            feature_class: "P".to_string(),
+            feature_code: "PPL".to_string(),
+            admin_code: admin_code.to_string(),
+            population: 1000,
+            lat,
+            lon,
+        };
+        let geocoder = ReverseGeocoder::new(vec![
+            city("Amsterdam", "NL", "07", 4.88969, 52.37403),
+            city("Haarlem", "NL", "07", 4.63683, 52.38084),
+            city("Antwerpen", "BE", "VLG", 4.40026, 51.21989),
+            city("Suva", "FJ", "C", 178.44149, -18.14161),
+        ])
+        .unwrap();
+
+        let place = geocoder.lookup(4.8285843, 52.3546274).unwrap();
+        assert_eq!(place.city.name, "Amsterdam");
+        assert_eq!(
+            (place.city.country.as_str(), place.city.admin_code.as_str()),
+            ("NL", "07")
+        );
+        let d = distance(
+            4.8285843,
+            52.3546274,
+            4.88969,
+            52.37403,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        assert_eq!(place.distance, d);
+
+        let batch = geocoder.lookup_batch(&[(4.5, 51.3), (-179.9, -18.0)]);
+        let names: Vec<&str> = batch
+            .iter()
+            .map(|p| p.unwrap().city.name.as_str())
+            .collect();
+        assert_eq!(names, ["Antwerpen", "Suva"]);
+
+        let empty = ReverseGeocoder::new(vec![]).unwrap();
+        assert!(empty.lookup(0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn geonames_tsv() {
+        let dump = "2759794\tAmsterdam\tAmsterdam\tAmstelodamum,Amsterdam\t52.37403\t4.88969\tP\tPPLC\tNL\t\t07\t0363\t\t\t741636\t\t13\tEurope/Amsterdam\t2022-08-25\n\
+                    # comments and empty lines are skipped\n\n\
+                    2198148\tSuva\tSuva\t\t-18.14161\t178.44149\tP\tPPLC\tFJ\t\tC\t\t\t\t77366\t\t-9999\tPacific/Fiji\t2019-09-05\n\
+                    1\tBroken\tBroken\t\tnorth\t0\tP\tPPL\tXX\t\t\t\t\t\t\t\t\t\t\n";
+        let records: Vec<_> = GeoNames::new(dump.as_bytes()).collect();
+        assert_eq!(records.len(), 3);
+
+        let amsterdam = records[0].as_ref().unwrap();
+        assert_eq!(amsterdam.geoname_id, 2759794);
+        assert_eq!(amsterdam.alternate_names, ["Amstelodamum", "Amsterdam"]);
+        assert_eq!((amsterdam.get_x(), amsterdam.get_y()), (4.88969, 52.37403));
+        assert_eq!(
+            (amsterdam.admin1_code.as_str(), amsterdam.population),
+            ("07", 741636)
+        );
+        assert_eq!((amsterdam.elevation, amsterdam.dem), (None, Some(13)));
+        assert_eq!(records[1].as_ref().unwrap().dem, Some(-9999));
+
+        let err = records[2].as_ref().unwrap_err().to_string();
+        assert!(err.starts_with("line 5: invalid latitude"), "{}", err);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let data = "name;lat;lon\n\
+                    Amsterdam;52.37403;4.88969\n\
+                    Nowhere;;4.0\n\
+                    Haarlem;52.38084; 4.63683\n\
+                    Short;52.0\n\
+                    Antwerpen;51.21989;4.40026\n";
+        let points = CsvLoader::new("lon", "lat")
+            .delimiter(b';')
+            .load::<HashMap<String, String>, _>(data.as_bytes())
+            .unwrap();
+        assert_eq!(points.index.points.len(), 3);
+        assert_eq!(points.index.points[1].record["name"], "Haarlem");
+        assert_eq!(points.index.points[1].get_x(), 4.63683);
+        let lines: Vec<u64> = points.bad_rows.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [3, 5]);
+
+        let by_position = CsvLoader::new(2, 1)
+            .delimiter(b';')
+            .has_headers(false)
+            .load::<(String, String, String), _>("Suva;-18.14161;178.44149\n".as_bytes())
+            .unwrap();
+        assert_eq!(by_position.index.points[0].record.0, "Suva");
+        assert!(CsvLoader::new("lng", "lat")
+            .load::<Vec<String>, _>(data.as_bytes())
+            .is_err());
+
+        let near = around_with_distance(
+            &points.index,
+            4.8285843,
+            52.3546274,
+            Some(1),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        let mut out = vec![];
+        let results = near
+            .iter()
+            .map(|&(id, _, d)| (id, Some(d)))
+            .chain(Some((2, None)));
+        write_results(&mut out, &points.index, results).unwrap();
+        let expected = format!(
+            "id,x,y,distance\n0,4.88969,52.37403,{}\n2,4.40026,51.21989,\n",
+            near[0].2
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn geojson_round_trip() {
+        let collection = r#"{
+            "type": "FeatureCollection",
+            "features": [
+                { "type": "Feature", "id": "ams",
+                  "geometry": { "type": "Point", "coordinates": [4.88969, 52.37403] },
+                  "properties": { "name": "Amsterdam", "index": "NL-NH" } },
+                { "type": "Feature",
+                  "geometry": { "type": "MultiPoint", "coordinates": [[4.63683, 52.38084], [4.40026, 51.21989, 10.0]] },
+                  "properties": { "name": "stops" } },
+                { "type": "Feature",
+                  "geometry": { "type": "LineString", "coordinates": [[0, 0], [1, 1]] },
+                  "properties": null }
+            ]
+        }"#;
+        let index = read_feature_collection(collection.as_bytes(), 8).unwrap();
+        assert_eq!(index.points.len(), 3);
+        assert_eq!(index.points[2].feature, 1);
+        assert_eq!(index.points[2].properties["name"], "stops");
+
+        let near = around_with_distance(
+            &index,
+            4.8285843,
+            52.3546274,
+            Some(2),
+            None,
+            &None,
+            Units::Kilometers,
+            Formula::Spherical,
+        );
+        let out = results_to_geojson(&index, near.iter().map(|&(id, _, d)| (id, Some(d))));
+        let features = out["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["id"], "ams");
+        assert_eq!(features[0]["properties"]["name"], "Amsterdam");
+        assert_eq!(features[0]["properties"]["index"], "NL-NH");
+        assert_eq!(features[0]["properties"]["kdbush:index"], 0);
+        assert_eq!(features[0]["properties"]["kdbush:distance"], near[0].2);
+        assert_eq!(features[1]["properties"]["kdbush:index"], 1);
+        assert_eq!(
+            features[1]["geometry"]["coordinates"],
+            serde_json::json!([4.63683, 52.38084])
+        );
+
+        let mut ids = vec![];
+        grid().range(&-1.0, &-1.0, &0.0, &0.0, &mut ids, None, None, None);
+        let out = results_to_geojson(&grid(), ids.iter().map(|&id| (id, None)));
+        assert_eq!(out["features"].as_array().unwrap().len(), 4);
+        assert!(out["features"][0]["properties"].get("kdbush:distance").is_none());
 
-    fn serialize_cities<P: AsRef<Path>>(path: P) -> Result<KDBush<City>, Box<Error>> {
-        println!("Opening cities json file...");
-        let mut s: String = "".to_string();
-        let f = File::open(path)?;
-        GzDecoder::new(f).read_to_string(&mut s).unwrap();
-        let cities: Vec<City> = serde_json::from_str(s.as_str())?;
-        println!("{:?}", cities[0]);
-        let cities: KDBush<City> = KDBush::new(
-            cities, // Box::new(|c: &City| Point(c.lon as f64, c.lat as f64)),
-            64,
-        ).unwrap();
-        Ok(cities)
+        let bad = r#"{ "type": "FeatureCollection", "features": [
+            { "type": "Feature", "geometry": { "type": "Point", "coordinates": ["x", 1] } } ] }"#;
+        assert!(read_feature_collection(bad.as_bytes(), 8).is_err());
     }
 
     #[test]
@@ -43,6 +1001,8 @@ mod tests {
             Some(15),
             None,
             &Some(Box::new(|c: &City| c.population > 15000)),
+            Units::Kilometers,
+            Formula::Spherical,
         );
         let names: Vec<String> = points.iter().map(|p| p.name.to_string()).collect();
         println!("{:?}", names);
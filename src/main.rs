@@ -122,7 +122,7 @@ fn main() {
 
 #[cfg(test)]
 mod tests {
-    use kdbush::kdbush::{RawCoord};
+    use kdbush::kdbush::{RawCoord, ValidationError};
 
     fn get_points() -> Vec<RawCoord<i16>> {
         vec![
@@ -293,4 +293,224 @@ mod tests {
         &sorted_kdb.within(50, 50, 20, &mut within_ids, None, None, None);
         assert_eq!(within_ids.is_empty(), true);
     }
+
+    #[test]
+    fn test_validate() {
+        let mut index = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        assert_eq!(index.validate(), Ok(()));
+
+        index.ids.swap(0, 99);
+        assert_eq!(
+            index.validate(),
+            Err(ValidationError::SplitOrder {
+                left: 0,
+                right: 99,
+                axis: 0,
+                index: 0,
+            })
+        );
+
+        index.ids[0] = index.ids[1];
+        assert_eq!(
+            index.validate(),
+            Err(ValidationError::NotAPermutation {
+                position: 1,
+                id: index.ids[1],
+            })
+        );
+
+        index.ids.pop();
+        assert_eq!(
+            index.validate(),
+            Err(ValidationError::LengthMismatch {
+                points: 100,
+                ids: 99,
+            })
+        );
+
+        let sorted = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        let copy = kdbush::kdbush::KDBush::from_parts(get_points(), 10, sorted.ids.clone());
+        assert_eq!(copy.unwrap().ids, sorted.ids);
+        let unsorted = (0..100).collect();
+        assert!(kdbush::kdbush::KDBush::from_parts(get_points(), 10, unsorted).is_err());
+    }
+
+    #[test]
+    fn test_nodes() {
+        let index = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        let nodes: Vec<_> = index.nodes().collect();
+
+        let root = &nodes[0];
+        assert_eq!((root.left, root.right, root.depth), (0, 99, 0));
+        assert_eq!((root.bbox.min_x, root.bbox.max_x), (1, 99));
+        assert_eq!(root.split, Some(index.points[index.ids[49]].0));
+
+        // every point is either a median of a branch or inside a leaf
+        let covered: usize = nodes
+            .iter()
+            .map(|n| if n.leaf { n.right - n.left + 1 } else { 1 })
+            .sum();
+        assert_eq!(covered, 100);
+
+        for node in &nodes {
+            for &id in &index.ids[node.left..node.right + 1] {
+                let p = &index.points[id];
+                assert!(p.0 >= node.bbox.min_x && p.0 <= node.bbox.max_x);
+                assert!(p.1 >= node.bbox.min_y && p.1 <= node.bbox.max_y);
+            }
+        }
+
+        let stats = index.stats();
+        assert_eq!(stats.nodes, nodes.len());
+        assert_eq!(stats.depth, 4);
+        assert_eq!(stats.leaves, 13);
+        assert_eq!(stats.leaf_fill.iter().sum::<usize>(), stats.leaves);
+    }
+
+    #[test]
+    fn test_top_k() {
+        let index = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        let score = |p: &RawCoord<i16>| i32::from(p.0) * 100 + i32::from(p.1);
+        let scores = index.score_bounds(score);
+
+        let mut expected = vec![];
+        index.range(&20, &30, &50, &70, &mut expected, None, None, None);
+        expected.sort_by_key(|&id| -score(&index.points[id]));
+        expected.truncate(5);
+        let top = index.range_top_k(&20, &30, &50, &70, 5, &scores).unwrap();
+        assert_eq!(top, expected);
+
+        let mut expected = vec![];
+        index.within(50, 50, 20, &mut expected, None, None, None);
+        expected.sort_by_key(|&id| -score(&index.points[id]));
+        expected.truncate(3);
+        assert_eq!(index.within_top_k(50, 50, 20, 3, &scores).unwrap(), expected);
+
+        let all = index.within_top_k(50, 50, 20, 100, &scores).unwrap();
+        assert_eq!(all.len(), 12);
+        let none = index.range_top_k(&20, &30, &50, &70, 0, &scores).unwrap();
+        assert!(none.is_empty());
+
+        // bounds of another index are rejected instead of indexing past them
+        let fewer = get_points().into_iter().take(50).collect();
+        let other = kdbush::kdbush::KDBush::new(fewer, 10).unwrap();
+        assert!(other.within_top_k(50, 50, 20, 3, &scores).is_err());
+    }
+
+    #[test]
+    fn test_periodic() {
+        let plane = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        let torus = kdbush::kdbush::KDBush::new(get_points(), 10)
+            .unwrap()
+            .with_period(100, 100)
+            .unwrap();
+        let sorted = |mut ids: Vec<usize>| {
+            ids.sort();
+            ids
+        };
+
+        let mut expected = vec![];
+        plane.range(&90, &90, &100, &100, &mut expected, None, None, None);
+        plane.range(&0, &90, &10, &100, &mut expected, None, None, None);
+        plane.range(&90, &0, &100, &10, &mut expected, None, None, None);
+        plane.range(&0, &0, &10, &10, &mut expected, None, None, None);
+        let mut range_ids = vec![];
+        torus.range(&90, &-10, &110, &10, &mut range_ids, None, None, None);
+        assert_eq!(sorted(range_ids), sorted(expected));
+
+        // minimum image distance on the 100x100 torus
+        let torus_dist = |p: &RawCoord<i16>, x: i16, y: i16| {
+            let dx = i32::from((p.0 - x).abs().min(100 - (p.0 - x).abs()));
+            let dy = i32::from((p.1 - y).abs().min(100 - (p.1 - y).abs()));
+            dx * dx + dy * dy
+        };
+        let expected: Vec<usize> = (0..100)
+            .filter(|&i| torus_dist(&torus.points[i], 95, 5) <= 15 * 15)
+            .collect();
+        let mut within_ids = vec![];
+        torus.within(95, 5, 15, &mut within_ids, None, None, None);
+        assert_eq!(sorted(within_ids.clone()), expected);
+
+        let score = |p: &RawCoord<i16>| i32::from(p.0) * 100 + i32::from(p.1);
+        let scores = torus.score_bounds(score);
+        let mut expected: Vec<i32> = within_ids.iter().map(|&i| -score(&torus.points[i])).collect();
+        expected.sort();
+        let top: Vec<i32> = torus
+            .within_top_k(95, 5, 15, 5, &scores)
+            .unwrap()
+            .iter()
+            .map(|&i| -score(&torus.points[i]))
+            .collect();
+        assert_eq!(top, expected[..5].to_vec());
+        let mut range_ids = vec![];
+        torus.range(&90, &-10, &110, &10, &mut range_ids, None, None, None);
+        assert_eq!(
+            torus.range_top_k(&90, &-10, &110, &10, 100, &scores).unwrap().len(),
+            range_ids.len()
+        );
+
+        let nearest = torus.nearest(-3, 97, Some(10), None);
+        let dists: Vec<i32> = nearest
+            .iter()
+            .map(|&i| torus_dist(&torus.points[i], 97, 97))
+            .collect();
+        let mut expected: Vec<i32> = torus
+            .points
+            .iter()
+            .map(|p| torus_dist(p, 97, 97))
+            .collect();
+        expected.sort();
+        assert_eq!(dists, expected[..10].to_vec());
+        assert!(torus.nearest(-3, 97, Some(0), None).is_empty());
+
+        assert_eq!(torus.period(), Some((100, 100)));
+        let outside = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        assert_eq!(
+            outside.with_period(50, 50).err(),
+            Some(ValidationError::OutsidePeriod { id: 0 })
+        );
+        let flat = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        assert_eq!(
+            flat.with_period(0, 100).err(),
+            Some(ValidationError::InvalidPeriod)
+        );
+        let empty = kdbush::kdbush::KDBush::<RawCoord<i16>>::new(vec![], 10)
+            .unwrap()
+            .with_period(100, 100)
+            .unwrap();
+        assert!(empty.nearest(50, 50, Some(3), None).is_empty());
+
+        let nearest = plane.nearest(50, 50, None, Some(20));
+        let mut within_ids = vec![];
+        plane.within(50, 50, 20, &mut within_ids, None, None, None);
+        assert_eq!(sorted(nearest), sorted(within_ids));
+    }
+
+    #[test]
+    fn test_within_polygon() {
+        let kdb = kdbush::kdbush::KDBush::new(get_points(), 10).unwrap();
+        // an L shape with a square hole
+        let polygon = vec![
+            vec![(10, 10), (60, 10), (60, 30), (30, 30), (30, 60), (10, 60), (10, 10)],
+            vec![(15, 15), (25, 15), (25, 25), (15, 25)],
+        ];
+        let on_edge = |p: &RawCoord<i16>| {
+            [10, 15, 25, 30, 60].contains(&p.0) || [10, 15, 25, 30, 60].contains(&p.1)
+        };
+        let inside = |p: &RawCoord<i16>| {
+            let in_l = (p.0 > 10 && p.0 < 60 && p.1 > 10 && p.1 < 30)
+                || (p.0 > 10 && p.0 < 30 && p.1 > 10 && p.1 < 60);
+            let in_hole = p.0 > 15 && p.0 < 25 && p.1 > 15 && p.1 < 25;
+            in_l && !in_hole
+        };
+
+        let mut ids = kdb.within_polygon(&polygon);
+        ids.retain(|&i| !on_edge(&kdb.points[i]));
+        ids.sort();
+        let expected: Vec<usize> = (0..kdb.points.len())
+            .filter(|&i| !on_edge(&kdb.points[i]) && inside(&kdb.points[i]))
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(ids, expected);
+    }
 }
@@ -0,0 +1,58 @@
+//! Reverse geocoding: the nearest populated place to a location, using the
+//! all-the-cities dataset.
+
+use std::io::Error;
+use std::path::Path;
+
+use crate::dataset::load_cities_json_gz;
+use crate::geokdbush::nearest_iter;
+use crate::kdbush::{City, KDBush};
+
+/// The place found for a location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Place<'a> {
+    /// Position of the place in the index.
+    pub id: usize,
+    /// The place with its country and administrative codes.
+    pub city: &'a City,
+    /// Great circle distance from the location in km.
+    pub distance: f64,
+}
+
+/// An index of places answering nearest place queries.
+#[derive(Debug)]
+pub struct ReverseGeocoder {
+    index: KDBush<City>,
+}
+
+impl ReverseGeocoder {
+    pub fn new(cities: Vec<City>) -> Result<ReverseGeocoder, Error> {
+        Ok(ReverseGeocoder {
+            index: KDBush::new(cities, 64)?,
+        })
+    }
+
+    /// Loads a gzipped all-the-cities `cities.json.gz`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ReverseGeocoder, Error> {
+        ReverseGeocoder::new(load_cities_json_gz(path)?)
+    }
+
+    pub fn index(&self) -> &KDBush<City> {
+        &self.index
+    }
+
+    /// The place nearest to `(lng, lat)`, `None` only if there are no places.
+    pub fn lookup(&self, lng: f64, lat: f64) -> Option<Place<'_>> {
+        nearest_iter(&self.index, lng, lat)
+            .next()
+            .map(|(id, city, distance)| Place { id, city, distance })
+    }
+
+    /// `lookup` for every `(lng, lat)` location, in order.
+    pub fn lookup_batch(&self, locations: &[(f64, f64)]) -> Vec<Option<Place<'_>>> {
+        locations
+            .iter()
+            .map(|&(lng, lat)| self.lookup(lng, lat))
+            .collect()
+    }
+}